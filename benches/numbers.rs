@@ -248,6 +248,21 @@ fn temp_from_bytes_checked(bytes: &[u8]) -> Option<i16> {
     if minus { Some(-(res)) } else { Some(res)}
 }
 
+#[test]
+fn all_i16_swar() {
+    #[allow(unused_variables)]
+    for (s, i) in TEMPER_NUMS_INT {
+        let v = one_brc::swar::i16_from_bytes_swar(s.as_bytes()).unwrap();
+        // assert_eq!(i, v);
+    }
+}
+
+/// i16 from one_brc::swar::i16_from_bytes_swar
+#[bench]
+fn bench_i16_swar(b: &mut Bencher) {
+    b.iter(move || all_i16_swar());
+}
+
 #[test]
 fn test_binary() {
     let b = [b'-', b'.', b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9'];
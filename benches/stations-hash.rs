@@ -9,6 +9,10 @@ use std::io::Read;
 use std::str::{from_utf8};
 use test::Bencher;
 use ahash::{AHashMap, AHashSet};
+use memchr::memrchr;
+use one_brc::{process_block_into, ParseOptions, TemperStat};
+use one_brc::diagnostics::Diagnostics;
+use one_brc::table::{hash_name, StationTable};
 
 #[test]
 fn test_read_stations() {
@@ -110,3 +114,82 @@ fn bench_stations_ahash(b: &mut Bencher) {
 
     b.iter(|| stations_ahash(&stations));
 }
+
+fn stations_table_hash(stations: &Vec<&[u8]>) -> u64 {
+    let mut res: u64 = 0;
+    for station in stations {
+        let h = hash_name(station);
+        res &= h;
+    }
+
+    res
+}
+
+#[bench]
+fn bench_stations_table_hash(b: &mut Bencher) {
+    let data = read_stations_file();
+    let stations = get_stations(&data);
+
+    b.iter(|| stations_table_hash(&stations));
+}
+
+#[test]
+fn test_table_hash_collisions() {
+    let data = read_stations_file();
+    let stations = get_stations(&data);
+
+    let set: HashSet<u64> = stations.iter().map(|s| hash_name(s)).collect();
+    println!("table hash set len: {}", set.len());
+}
+
+/// Lines of all stations, every station repeated 10 times
+fn stations_lines(stations: &Vec<&[u8]>) -> Vec<u8> {
+    let mut lines = Vec::new();
+    for _ in 0..10 {
+        for station in stations {
+            lines.extend_from_slice(station);
+            lines.extend_from_slice(b";12.3\n");
+        }
+    }
+    lines
+}
+
+fn stations_ahash_update(lines: &[u8]) -> usize {
+    let mut map: AHashMap<&[u8], TemperStat> = AHashMap::with_capacity(10_000);
+    for line in lines.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        let col = memrchr(b';', line).unwrap();
+        let name = &line[..col];
+        if let Some(s) = map.get_mut(name) {
+            s.update(123);
+        } else {
+            map.insert(name, TemperStat::from_i16(123));
+        }
+    }
+    map.len()
+}
+
+/// Table update as production runs it: the record scan of the active backend, the name hash
+/// and the temperature parsing of `process_block_into`
+fn stations_table_update(lines: &[u8]) -> usize {
+    let mut table = StationTable::<TemperStat>::new(10_000);
+    process_block_into(lines, &mut table, ParseOptions::default(), &mut Diagnostics::default());
+    table.len()
+}
+
+#[bench]
+fn bench_stations_ahash_update(b: &mut Bencher) {
+    let data = read_stations_file();
+    let lines = stations_lines(&get_stations(&data));
+
+    b.iter(|| stations_ahash_update(&lines));
+}
+
+#[bench]
+fn bench_stations_table_update(b: &mut Bencher) {
+    let data = read_stations_file();
+    let stations = get_stations(&data);
+    let lines = stations_lines(&stations);
+    assert_eq!(stations_ahash_update(&lines), stations_table_update(&lines));
+
+    b.iter(|| stations_table_update(&lines));
+}
@@ -0,0 +1,40 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::Instant;
+use memmap::Mmap;
+use one_brc::FILE_PATH;
+use one_brc::backend::Masks;
+use one_brc::scan::Records;
+
+fn main() {
+    let start = Instant::now();
+    let file = File::open(Path::new(FILE_PATH)).unwrap();
+
+    let mmap = unsafe { Mmap::map(&file).unwrap() };
+
+    println!("{}", mmap.len());
+
+    let data = &mmap[..];
+    #[cfg(target_arch = "x86_64")]
+    let (lines, name_bytes) = match one_brc::backend::Avx2Masks::new() {
+        Some(masks) => count(Records::with_masks(data, masks)),
+        None => count(Records::new(data)),
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let (lines, name_bytes) = count(Records::new(data));
+
+    println!("lines: {lines}, name bytes: {name_bytes}, elapsed: {:?}", start.elapsed());
+}
+
+/// Lines and bytes of station names
+fn count<M: Masks>(records: Records<M>) -> (usize, usize) {
+    let mut lines = 0;
+    let mut name_bytes = 0;
+    for (name, temp) in records {
+        lines += 1;
+        if temp.is_some() {
+            name_bytes += name.len();
+        }
+    }
+    (lines, name_bytes)
+}
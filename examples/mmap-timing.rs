@@ -6,7 +6,7 @@ use std::thread::available_parallelism;
 use std::time::{Duration, Instant};
 use ahash::AHashMap;
 use memmap::Mmap;
-use one_brc::{FILE_PATH, LINE_MAX_LEN, process_block};
+use one_brc::{FILE_PATH, LINE_MAX_LEN, process_block, TemperStat};
 use one_brc::result::TemperStatResult;
 
 fn main() {
@@ -46,7 +46,7 @@ fn main() {
             let h = s.spawn({
                 let tx = tx.clone();
                 move || {
-                    let (map, lines, errors) = process_block(cur, 7000);
+                    let (map, lines, errors) = process_block::<TemperStat>(cur, 7000);
                     tx.send(id).unwrap();
                     (Instant::now(), map, lines, errors)
                 }
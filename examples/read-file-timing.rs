@@ -4,7 +4,7 @@ use std::path::Path;
 use std::thread;
 use std::time::{Duration, Instant};
 use sysinfo::{MemoryRefreshKind, RefreshKind, System};
-use one_brc::{FILE_PATH, process_block};
+use one_brc::{FILE_PATH, process_block, TemperStat};
 use one_brc::result::TemperStatResult;
 
 /// Read file into memory
@@ -103,7 +103,7 @@ fn main() {
 
         bufs.iter().for_each(|block| {
             let handle = scope.spawn(move || {
-                let (map, lines, errors) = process_block(block.as_slice(), 7000);
+                let (map, lines, errors) = process_block::<TemperStat>(block.as_slice(), 7000);
                 (Instant::now(), map, lines, errors)
             });
 
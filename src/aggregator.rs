@@ -0,0 +1,107 @@
+//! Per-station statistics plugged into the parsing and aggregation code.
+//!
+//! `process_block`, `StationTable`, `TemperStatResult` and `process_files` are generic over
+//! an `Aggregator`, `TemperStat` is the default one. Output formats other than the 1BRC one
+//! are specific to `TemperStat`.
+use std::fmt::Formatter;
+use crate::{ParseOptions, TemperStat};
+
+/// Statistics of the temperatures of one station, values are in tenths of degree
+pub trait Aggregator: Clone + Default {
+    /// Statistics of the first value of a station
+    fn init(v: i16) -> Self;
+
+    /// Statistics of the first value with the optional statistics requested by `options`,
+    /// e.g. histograms, `init` by default
+    fn init_with(v: i16, _options: ParseOptions) -> Self {
+        Self::init(v)
+    }
+
+    fn update(&mut self, v: i16);
+
+    /// Add statistics of the same station computed from other lines
+    fn merge(&mut self, other: &Self);
+
+    /// Write the final statistics of the 1BRC output, e.g. `min/mean/max`
+    fn format(&self, f: &mut Formatter<'_>) -> std::fmt::Result;
+}
+
+impl Aggregator for TemperStat {
+    #[inline]
+    fn init(v: i16) -> Self {
+        TemperStat::from_i16(v)
+    }
+
+    fn init_with(v: i16, options: ParseOptions) -> Self {
+        TemperStat::from_i16_with(v, options)
+    }
+
+    #[inline]
+    fn update(&mut self, v: i16) {
+        TemperStat::update(self, v)
+    }
+
+    fn merge(&mut self, other: &Self) {
+        TemperStat::merge(self, other)
+    }
+
+    fn format(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+#[test]
+fn test_custom_aggregator() {
+    use std::num::NonZeroUsize;
+    use crate::parallel::process_files;
+    use crate::result::TemperStatResult;
+    use crate::test::TEST_STR_100;
+    use crate::ParseOptions;
+
+    /// Readings below freezing out of all readings
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Freezing {
+        below: usize,
+        count: usize,
+    }
+
+    impl Aggregator for Freezing {
+        fn init(v: i16) -> Self {
+            Freezing { below: (v < 0) as usize, count: 1 }
+        }
+
+        fn update(&mut self, v: i16) {
+            self.below += (v < 0) as usize;
+            self.count += 1;
+        }
+
+        fn merge(&mut self, other: &Self) {
+            self.below += other.below;
+            self.count += other.count;
+        }
+
+        fn format(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}/{}", self.below, self.count)
+        }
+    }
+
+    let data = [TEST_STR_100, "Kunming;-0.1\nTauranga;0.0\n"].concat().repeat(7);
+    let files = [data.as_bytes(), TEST_STR_100.as_bytes()];
+    let results = process_files::<Freezing>(&files, NonZeroUsize::new(3).unwrap(), 64, ParseOptions::default(), 0);
+
+    let mut r = TemperStatResult::new();
+    results.iter().for_each(|f| r.merge(&f.result));
+    let station = |name: &str| r.iter().find(|(s, _)| *s == name.as_bytes()).map(|(_, a)| a.clone());
+    assert_eq!(Some(Freezing { below: 15, count: 23 }), station("Kunming"));
+    assert_eq!(Some(Freezing { below: 0, count: 15 }), station("Tauranga"));
+    assert_eq!(Some(Freezing { below: 8, count: 8 }), station("Tokyo"));
+    assert!(r.to_string().contains(", Kunming=15/23, "));
+
+    // the same statistics as computed by `TemperStat`
+    let (stats, _, _) = crate::process_block::<TemperStat>(TEST_STR_100.as_bytes(), 100);
+    let (freezing, _, _) = crate::process_block::<Freezing>(TEST_STR_100.as_bytes(), 100);
+    assert_eq!(stats.len(), freezing.len());
+    for (name, f) in freezing.iter() {
+        assert_eq!(stats.get(name).unwrap().count(), f.count);
+    }
+}
@@ -0,0 +1,328 @@
+//! CPU specific implementations of the hot parsing path.
+//!
+//! The backend is selected once at startup: the best one supported by the CPU, or the one
+//! forced by `Backend::set_active` (the `--backend` option) or the `ONE_BRC_BACKEND`
+//! environment variable, e.g. to compare the backends in benchmarks.
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Environment variable forcing the backend
+pub const BACKEND_ENV: &str = "ONE_BRC_BACKEND";
+
+/// Bytes scanned at once
+pub const WINDOW: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Portable SWAR, 8 bytes at a time
+    Scalar,
+    /// 16 bytes compares, x86-64 with SSE2
+    Sse2,
+    /// 32 bytes compares, x86-64 with AVX2
+    Avx2,
+    /// 16 bytes compares, aarch64
+    Neon,
+}
+
+/// Active backend, `0` is not selected yet
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+
+impl Backend {
+    pub const ALL: [Backend; 4] = [Backend::Scalar, Backend::Sse2, Backend::Avx2, Backend::Neon];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Scalar => "scalar",
+            Backend::Sse2 => "sse2",
+            Backend::Avx2 => "avx2",
+            Backend::Neon => "neon",
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Backend::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Backends supported by the CPU, the fastest is the last
+    pub fn supported() -> Vec<Backend> {
+        Backend::ALL.into_iter().filter(Backend::is_supported).collect()
+    }
+
+    /// The fastest backend supported by the CPU
+    pub fn detect() -> Backend {
+        *Backend::supported().last().unwrap()
+    }
+
+    /// Backend forced by `ONE_BRC_BACKEND`, error if the value is unknown or not supported
+    pub fn from_env() -> Result<Option<Backend>, String> {
+        match std::env::var(BACKEND_ENV) {
+            Ok(v) if !v.is_empty() => v.parse::<Backend>().and_then(Backend::check).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn check(self) -> Result<Backend, String> {
+        if self.is_supported() { Ok(self) } else { Err(format!("backend `{self}` is not supported by this CPU")) }
+    }
+
+    /// Force the backend for the rest of the run
+    pub fn set_active(backend: Backend) -> Result<(), String> {
+        backend.check()?;
+        ACTIVE.store(backend as u8 + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Backend used by `process_block`: forced one, `ONE_BRC_BACKEND` if valid, or detected
+    #[inline]
+    pub fn active() -> Backend {
+        match ACTIVE.load(Ordering::Relaxed) {
+            0 => {
+                let backend = Backend::from_env().ok().flatten().unwrap_or_else(Backend::detect);
+                ACTIVE.store(backend as u8 + 1, Ordering::Relaxed);
+                backend
+            }
+            n => Backend::ALL[n as usize - 1],
+        }
+    }
+
+    /// Bitmasks of `;` and `\n` positions of a window, dispatched on every call.
+    /// The scan loop uses the `Masks` types instead, to dispatch once per block.
+    pub fn masks(&self, w: &[u8; WINDOW]) -> (u64, u64) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse2 => Sse2Masks::new().map(|m| m.masks(w)),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => Avx2Masks::new().map(|m| m.masks(w)),
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => NeonMasks::new().map(|m| m.masks(w)),
+            _ => None,
+        }.unwrap_or_else(|| ScalarMasks.masks(w))
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Backend::ALL.into_iter().find(|b| b.name() == s).ok_or_else(|| format!("unknown backend `{s}`"))
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Bitmasks of `;` and `\n` positions of a window by one backend.
+///
+/// Every backend is a zero sized type, so the scan loop generic over `Masks` is monomorphized
+/// and the masks are inlined into it. The SIMD types are created only if the CPU supports them.
+pub trait Masks: Copy {
+    fn masks(self, w: &[u8; WINDOW]) -> (u64, u64);
+}
+
+/// Portable SWAR masks
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScalarMasks;
+
+impl Masks for ScalarMasks {
+    #[inline(always)]
+    fn masks(self, w: &[u8; WINDOW]) -> (u64, u64) {
+        masks_scalar(w)
+    }
+}
+
+#[inline(always)]
+fn masks_scalar(w: &[u8; WINDOW]) -> (u64, u64) {
+    const LOW: u64 = 0x7F7F_7F7F_7F7F_7F7F;
+    const ONES: u64 = 0x0101_0101_0101_0101;
+
+    /// Bit per byte equal to zero, exact for every byte
+    #[inline(always)]
+    fn zero_bits(x: u64) -> u64 {
+        let high = !(((x & LOW) + LOW) | x | LOW);
+        // gather the high bits of the bytes into the lowest byte
+        (high >> 7).wrapping_mul(0x0102_0408_1020_4080) >> 56
+    }
+
+    let (mut semi, mut nl) = (0, 0);
+    for (i, c) in w.chunks_exact(8).enumerate() {
+        let x = u64::from_le_bytes(c.try_into().unwrap());
+        semi |= zero_bits(x ^ (ONES * b';' as u64)) << (i * 8);
+        nl |= zero_bits(x ^ (ONES * b'\n' as u64)) << (i * 8);
+    }
+    (semi, nl)
+}
+
+/// SSE2 masks, x86-64 only
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug)]
+pub struct Sse2Masks(());
+
+#[cfg(target_arch = "x86_64")]
+impl Sse2Masks {
+    pub fn new() -> Option<Self> {
+        Backend::Sse2.is_supported().then_some(Sse2Masks(()))
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Masks for Sse2Masks {
+    #[inline(always)]
+    fn masks(self, w: &[u8; WINDOW]) -> (u64, u64) {
+        // SAFETY: created only when SSE2 is detected
+        unsafe { masks_sse2(w) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+#[inline]
+unsafe fn masks_sse2(w: &[u8; WINDOW]) -> (u64, u64) {
+    use std::arch::x86_64::*;
+
+    let semi = _mm_set1_epi8(b';' as i8);
+    let nl = _mm_set1_epi8(b'\n' as i8);
+    let (mut s, mut n) = (0, 0);
+    for i in 0..4 {
+        let v = _mm_loadu_si128(w.as_ptr().add(i * 16) as *const __m128i);
+        s |= (_mm_movemask_epi8(_mm_cmpeq_epi8(v, semi)) as u16 as u64) << (i * 16);
+        n |= (_mm_movemask_epi8(_mm_cmpeq_epi8(v, nl)) as u16 as u64) << (i * 16);
+    }
+    (s, n)
+}
+
+/// AVX2 masks, x86-64 only
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug)]
+pub struct Avx2Masks(());
+
+#[cfg(target_arch = "x86_64")]
+impl Avx2Masks {
+    pub fn new() -> Option<Self> {
+        Backend::Avx2.is_supported().then_some(Avx2Masks(()))
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Masks for Avx2Masks {
+    #[inline(always)]
+    fn masks(self, w: &[u8; WINDOW]) -> (u64, u64) {
+        // SAFETY: created only when AVX2 is detected
+        unsafe { masks_avx2(w) }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn masks_avx2(w: &[u8; WINDOW]) -> (u64, u64) {
+    use std::arch::x86_64::*;
+
+    let semi = _mm256_set1_epi8(b';' as i8);
+    let nl = _mm256_set1_epi8(b'\n' as i8);
+    let lo = _mm256_loadu_si256(w.as_ptr() as *const __m256i);
+    let hi = _mm256_loadu_si256(w.as_ptr().add(32) as *const __m256i);
+    let mask = |v, c| _mm256_movemask_epi8(_mm256_cmpeq_epi8(v, c)) as u32 as u64;
+    (mask(lo, semi) | mask(hi, semi) << 32, mask(lo, nl) | mask(hi, nl) << 32)
+}
+
+/// NEON masks, aarch64 only
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone, Copy, Debug)]
+pub struct NeonMasks(());
+
+#[cfg(target_arch = "aarch64")]
+impl NeonMasks {
+    pub fn new() -> Option<Self> {
+        Backend::Neon.is_supported().then_some(NeonMasks(()))
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Masks for NeonMasks {
+    #[inline(always)]
+    fn masks(self, w: &[u8; WINDOW]) -> (u64, u64) {
+        // SAFETY: created only when NEON is detected
+        unsafe { masks_neon(w) }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+#[inline]
+unsafe fn masks_neon(w: &[u8; WINDOW]) -> (u64, u64) {
+    use std::arch::aarch64::*;
+
+    const BITS: [u8; 16] = [1, 2, 4, 8, 16, 32, 64, 128, 1, 2, 4, 8, 16, 32, 64, 128];
+    let bits = vld1q_u8(BITS.as_ptr());
+    let v = [0, 16, 32, 48].map(|i| vld1q_u8(w.as_ptr().add(i)));
+
+    // no movemask on NEON: weight the compare result bytes by bit and add them pairwise
+    let mask = |c: u8| {
+        let c = vdupq_n_u8(c);
+        let [a, b, d, e] = v.map(|v| vandq_u8(vceqq_u8(v, c), bits));
+        let s = vpaddq_u8(vpaddq_u8(a, b), vpaddq_u8(d, e));
+        vgetq_lane_u64::<0>(vreinterpretq_u64_u8(vpaddq_u8(s, s)))
+    };
+    (mask(b';'), mask(b'\n'))
+}
+
+#[test]
+fn test_masks() {
+    let mut w = [b'a'; WINDOW];
+    for (i, b) in [(0, b';'), (7, b'\n'), (8, b';'), (31, b'\n'), (32, b';'), (62, b';'), (63, b'\n')] {
+        w[i] = b;
+    }
+    w[20] = 0xbb; // `;` | 0x80
+    w[21] = 0x8a; // `\n` | 0x80
+    let expected = ((1 << 0) | (1 << 8) | (1 << 32) | (1 << 62), (1 << 7) | (1 << 31) | (1 << 63));
+    for b in Backend::supported() {
+        assert_eq!(expected, b.masks(&w), "{b}");
+    }
+}
+
+#[test]
+fn test_backend() {
+    assert_eq!(Backend::Scalar, Backend::supported()[0]);
+    assert!(Backend::detect().is_supported());
+    for b in Backend::ALL {
+        assert_eq!(Ok(b), b.name().parse());
+    }
+    assert!("sse4.2".parse::<Backend>().is_err());
+    #[cfg(target_arch = "x86_64")]
+    assert!(Backend::set_active(Backend::Neon).is_err());
+}
+
+#[test]
+fn test_backends_results() {
+    use crate::diagnostics::Diagnostics;
+    use crate::result::TemperStatResult;
+    use crate::table::StationTable;
+    use crate::{process_block_with, ParseOptions};
+    use crate::test::TEST_STR_100;
+
+    let long = format!("{};1.0\n{};2.0\nbad;1.0\r\n", "x".repeat(150), "y;".repeat(70));
+    let data = [TEST_STR_100, "a;b;1.0\nno delimiter\n;\n\n;;\n", &long].concat().repeat(5);
+
+    let results: Vec<(String, String)> = Backend::supported().into_iter().map(|b| {
+        let mut map: StationTable = StationTable::new(100);
+        let stats = process_block_with(b, data.as_bytes(), &mut map, ParseOptions::default(), &mut Diagnostics::default());
+        let mut r = TemperStatResult::new();
+        r.aggregate(&map);
+        (r.to_string(), stats.to_string())
+    }).collect();
+    assert!(results.windows(2).all(|w| w[0] == w[1]), "{results:?}");
+}
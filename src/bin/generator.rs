@@ -1,16 +1,416 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::f64::consts::TAU;
 use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread::{self, available_parallelism};
 use std::time::Instant;
-use rand::Rng;
-use one_brc::STATION_NAME_MAX_LEN;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use one_brc::{STATIONS_MAX, STATION_NAME_MAX_LEN};
+use one_brc::cli::parse_value;
 
 const REQUIRED_KEYS: usize = 10_000;
 const LINES_IN_TARGET: usize = 1_000_000_000;
 const TARGET_FILE_NAME: &str = "measurements.txt";
 const STATION_PATH: &str = "stations2.txt";
+/// Temperatures are uniform in the station mean ± `SPREAD`
+const SPREAD: f32 = 20.0;
+/// Standard deviation of Gaussian temperatures
+const SIGMA: f32 = 10.0;
+/// Zipf exponent of `--popularity zipf`
+const ZIPF_EXPONENT: f64 = 1.0;
+/// Rate of malformed lines of the `malformed` preset
+const MALFORMED_RATE: f64 = 0.01;
 
 const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+/// Lines formatted by a worker at once. Every block has its own random stream,
+/// so the output doesn't depend on the number of threads.
+const BLOCK_LINES: usize = 1 << 16;
+
+/// Blocks per thread formatted ahead of the writer
+const BLOCKS_AHEAD: usize = 2;
+
+/// Generator PRNG, the algorithm is fixed so a seed gives the same output with any `rand` version
+type GenRng = ChaCha8Rng;
+
+/// Most derived keys of a station: `name-1` to `name-9`
+const MAX_SUFFIX: usize = 9;
+/// Temperature limit of the rules, generated values are clamped to ±`MAX_TEMP`
+const MAX_TEMP: f32 = 99.9;
+const MAX_TENTHS: i16 = 999;
+/// Values of `--values boundary`: the clamp limits and the values where the text length changes
+const BOUNDARY_TENTHS: [i16; 11] = [-999, -998, -100, -99, -1, 0, 1, 99, 100, 998, 999];
+
+const USAGE: &str = "\
+Usage: generator [OPTIONS]
+
+Generate a 1BRC measurements file from station names with mean temperatures.
+If more keys than stations are requested, keys `name-1` to `name-9` are derived from station names.
+The same seed, stations file and options give a byte-identical output with any number of threads.
+
+Options:
+  -n, --lines <N>          Number of lines [default: 1000000000]
+  -k, --keys <N>           Number of unique station names [default: 10000]
+      --stations <PATH>    Stations file of `name;mean` lines [default: stations2.txt]
+  -o, --output <PATH>      Output file [default: measurements.txt]
+      --popularity <DIST>  Station popularity: `uniform` round-robin or `zipf[:S]` with exponent S [default: uniform]
+      --temperature <DIST> Temperatures around the station mean: `uniform` or `gaussian` [default: uniform]
+      --spread <DEGREES>   Uniform temperatures are in mean ± spread, clamped to ±99.9 [default: 20.0]
+      --sigma <DEGREES>    Standard deviation of Gaussian temperatures [default: 10.0]
+      --seasonal <DEGREES> Amplitude of a yearly drift of the means, one year over the file [default: 0.0]
+      --names <STYLE>      Station names: `stations`, `long` of 100 bytes or `common-prefix` [default: stations]
+      --values <STYLE>     Temperatures: `normal`, all `negative` or `boundary` values [default: normal]
+      --malformed <RATE>   Fraction of malformed lines, for the error paths of the parser [default: 0]
+      --preset <LIST>      Comma separated edge case presets, options after it override it:
+                           long-names, common-prefix, max-keys, negative, boundary, malformed
+      --buffer-size <BYTES>
+                           Write buffer size [default: 4194304]
+      --seed <N>           Seed of the random generator [default: random, printed]
+  -t, --threads <N>        Number of formatting threads [default: available parallelism]
+  -h, --help               Print this help
+";
+
+/// Command line arguments of the generator
+#[derive(Clone, Debug, PartialEq)]
+struct Args {
+    lines: usize,
+    keys: usize,
+    stations: PathBuf,
+    output: PathBuf,
+    popularity: Popularity,
+    temperature: TemperatureDist,
+    spread: f32,
+    sigma: f32,
+    seasonal: f32,
+    names: NameStyle,
+    values: ValueStyle,
+    malformed: f64,
+    buffer_size: usize,
+    seed: Option<u64>,
+    threads: Option<NonZeroUsize>,
+    help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            lines: LINES_IN_TARGET,
+            keys: REQUIRED_KEYS,
+            stations: PathBuf::from(STATION_PATH),
+            output: PathBuf::from(TARGET_FILE_NAME),
+            popularity: Popularity::Uniform,
+            temperature: TemperatureDist::Uniform,
+            spread: SPREAD,
+            sigma: SIGMA,
+            seasonal: 0.0,
+            names: NameStyle::Stations,
+            values: ValueStyle::Normal,
+            malformed: 0.0,
+            buffer_size: BUFFER_SIZE,
+            seed: None,
+            threads: None,
+            help: false,
+        }
+    }
+}
+
+impl Args {
+    /// Parse arguments without the program name, the stations file is checked by `validate`
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut result = Args::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+                _ => (arg.clone(), None),
+            };
+
+            let mut value = |name: &str| -> Result<String, String> {
+                inline.clone().or_else(|| args.next()).ok_or(format!("missing value for `{name}`"))
+            };
+
+            match flag.as_str() {
+                "-h" | "--help" => result.help = true,
+                "-n" | "--lines" => result.lines = parse_value(&flag, &value(&flag)?)?,
+                "-k" | "--keys" => result.keys = parse_value(&flag, &value(&flag)?)?,
+                "--stations" => result.stations = PathBuf::from(value(&flag)?),
+                "-o" | "--output" => result.output = PathBuf::from(value(&flag)?),
+                "--popularity" => result.popularity = parse_value(&flag, &value(&flag)?)?,
+                "--temperature" => result.temperature = parse_value(&flag, &value(&flag)?)?,
+                "--spread" => result.spread = parse_value(&flag, &value(&flag)?)?,
+                "--sigma" => result.sigma = parse_value(&flag, &value(&flag)?)?,
+                "--seasonal" => result.seasonal = parse_value(&flag, &value(&flag)?)?,
+                "--names" => result.names = parse_value(&flag, &value(&flag)?)?,
+                "--values" => result.values = parse_value(&flag, &value(&flag)?)?,
+                "--malformed" => result.malformed = parse_value(&flag, &value(&flag)?)?,
+                "--preset" => for preset in value(&flag)?.split(',') {
+                    preset.parse::<Preset>()?.apply(&mut result);
+                },
+                "--buffer-size" => result.buffer_size = parse_value(&flag, &value(&flag)?)?,
+                "--seed" => result.seed = Some(parse_value(&flag, &value(&flag)?)?),
+                "-t" | "--threads" => result.threads = Some(parse_value(&flag, &value(&flag)?)?),
+                _ => return Err(format!("unknown option `{arg}`")),
+            }
+        }
+
+        if result.keys == 0 {
+            return Err("`--keys` must be at least 1".to_string());
+        }
+        if result.keys > result.lines {
+            return Err(format!("{} keys can't be written in {} lines", result.keys, result.lines));
+        }
+        if !(0.0..=2.0 * MAX_TEMP).contains(&result.spread) {
+            return Err(format!("`--spread` must be between 0 and {:.1}", 2.0 * MAX_TEMP));
+        }
+        if !(0.0..=2.0 * MAX_TEMP).contains(&result.sigma) {
+            return Err(format!("`--sigma` must be between 0 and {:.1}", 2.0 * MAX_TEMP));
+        }
+        if !(0.0..=2.0 * MAX_TEMP).contains(&result.seasonal) {
+            return Err(format!("`--seasonal` must be between 0 and {:.1}", 2.0 * MAX_TEMP));
+        }
+        if !(0.0..=1.0).contains(&result.malformed) {
+            return Err("`--malformed` must be between 0 and 1".to_string());
+        }
+        if result.buffer_size == 0 {
+            return Err("`--buffer-size` must be at least 1".to_string());
+        }
+        Ok(result)
+    }
+
+    /// Check the arguments against the stations
+    fn validate(&self, stations: &[WeatherStation]) -> Result<(), String> {
+        let max = max_keys(stations);
+        if self.keys > max {
+            return Err(format!("{} keys requested, at most {max} keys can be derived from {}", self.keys, self.stations.display()));
+        }
+        Ok(())
+    }
+}
+
+/// How often stations occur in the output
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Popularity {
+    /// Stations in turn, all have the same number of lines
+    Uniform,
+    /// Station of rank `k` is drawn with weight `1 / k^s`, the first key is the most popular
+    Zipf(f64),
+}
+
+impl FromStr for Popularity {
+    type Err = String;
+
+    /// `uniform`, `zipf` or `zipf:S`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "uniform" => Ok(Popularity::Uniform),
+            None if s == "zipf" => Ok(Popularity::Zipf(ZIPF_EXPONENT)),
+            Some(("zipf", e)) => match e.parse::<f64>() {
+                Ok(e) if e > 0.0 && e.is_finite() => Ok(Popularity::Zipf(e)),
+                _ => Err(format!("invalid Zipf exponent `{e}`")),
+            },
+            _ => Err(format!("unknown popularity `{s}`, expected `uniform` or `zipf[:S]`")),
+        }
+    }
+}
+
+/// Distribution of temperatures around the station mean
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TemperatureDist {
+    /// Uniform in mean ± spread
+    Uniform,
+    /// Normal with standard deviation sigma
+    Gaussian,
+}
+
+impl FromStr for TemperatureDist {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(TemperatureDist::Uniform),
+            "gaussian" => Ok(TemperatureDist::Gaussian),
+            _ => Err(format!("unknown temperature distribution `{s}`, expected `uniform` or `gaussian`")),
+        }
+    }
+}
+
+/// Station names of the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NameStyle {
+    /// Names of the stations file and derived `name-N` names
+    Stations,
+    /// Names filled up to `STATION_NAME_MAX_LEN` bytes, multi-byte characters are kept
+    Long,
+    /// Names of `STATION_NAME_MAX_LEN` bytes with the same prefix, consecutive keys differ
+    /// only in the last byte
+    CommonPrefix,
+}
+
+impl FromStr for NameStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stations" => Ok(NameStyle::Stations),
+            "long" => Ok(NameStyle::Long),
+            "common-prefix" => Ok(NameStyle::CommonPrefix),
+            _ => Err(format!("unknown name style `{s}`, expected `stations`, `long` or `common-prefix`")),
+        }
+    }
+}
+
+/// Temperature values of the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ValueStyle {
+    /// Values of the temperature distribution
+    Normal,
+    /// Values of the distribution made negative, at least `-0.1`
+    Negative,
+    /// Values of `BOUNDARY_TENTHS` only
+    Boundary,
+}
+
+impl FromStr for ValueStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(ValueStyle::Normal),
+            "negative" => Ok(ValueStyle::Negative),
+            "boundary" => Ok(ValueStyle::Boundary),
+            _ => Err(format!("unknown value style `{s}`, expected `normal`, `negative` or `boundary`")),
+        }
+    }
+}
+
+/// Edge case data sets, a preset sets some of the options
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Preset {
+    LongNames,
+    CommonPrefix,
+    /// `STATIONS_MAX` keys, the most the rules allow
+    MaxKeys,
+    Negative,
+    Boundary,
+    Malformed,
+}
+
+impl Preset {
+    fn apply(&self, args: &mut Args) {
+        match self {
+            Preset::LongNames => args.names = NameStyle::Long,
+            Preset::CommonPrefix => args.names = NameStyle::CommonPrefix,
+            Preset::MaxKeys => args.keys = STATIONS_MAX,
+            Preset::Negative => args.values = ValueStyle::Negative,
+            Preset::Boundary => args.values = ValueStyle::Boundary,
+            Preset::Malformed => args.malformed = MALFORMED_RATE,
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "long-names" => Ok(Preset::LongNames),
+            "common-prefix" => Ok(Preset::CommonPrefix),
+            "max-keys" => Ok(Preset::MaxKeys),
+            "negative" => Ok(Preset::Negative),
+            "boundary" => Ok(Preset::Boundary),
+            "malformed" => Ok(Preset::Malformed),
+            _ => Err(format!("unknown preset `{s}`")),
+        }
+    }
+}
+
+/// Distributions of the output lines
+#[derive(Clone, Debug)]
+struct Distribution {
+    /// Cumulative Zipf weights of the keys, `None` for round-robin
+    zipf: Option<Vec<f64>>,
+    temperature: TemperatureDist,
+    spread: f32,
+    sigma: f32,
+    seasonal: f32,
+    /// Lines of a seasonal cycle
+    year: usize,
+    values: ValueStyle,
+    /// Fraction of malformed lines
+    malformed: f64,
+}
+
+impl Distribution {
+    fn new(args: &Args) -> Self {
+        let zipf = match args.popularity {
+            Popularity::Uniform => None,
+            Popularity::Zipf(e) => Some((1..=args.keys)
+                .scan(0.0, |sum, k| {
+                    *sum += (k as f64).powf(-e);
+                    Some(*sum)
+                })
+                .collect()),
+        };
+        Distribution { zipf, temperature: args.temperature, spread: args.spread, sigma: args.sigma, seasonal: args.seasonal, year: args.lines, values: args.values, malformed: args.malformed }
+    }
+
+    /// Key of line `row`, `next` is the next key of round-robin.
+    /// With Zipf popularity every key is written once first, so all keys are in the output.
+    #[inline]
+    fn key(&self, row: usize, next: &mut usize, keys: usize, rng: &mut GenRng) -> usize {
+        match &self.zipf {
+            Some(cdf) if row >= keys => {
+                let w = rng.gen::<f64>() * cdf[cdf.len() - 1];
+                cdf.partition_point(|&c| c <= w).min(keys - 1)
+            }
+            _ => {
+                let k = *next;
+                *next = if k + 1 == keys { 0 } else { k + 1 };
+                k
+            }
+        }
+    }
+
+    /// Temperature in tenths of line `row` of a station with `mean`
+    #[inline]
+    fn temperature(&self, row: usize, mean: f32, rng: &mut GenRng) -> i16 {
+        let mean = if self.seasonal == 0.0 { mean } else { mean + self.seasonal * (TAU * row as f64 / self.year as f64).sin() as f32 };
+        let temp = match self.temperature {
+            TemperatureDist::Uniform => rng.gen_range(tenths(mean - self.spread)..=tenths(mean + self.spread)),
+            TemperatureDist::Gaussian => tenths(mean + self.sigma * gaussian(rng)),
+        };
+        let temp = temp.clamp(-MAX_TENTHS, MAX_TENTHS);
+        match self.values {
+            ValueStyle::Normal => temp,
+            ValueStyle::Negative => -temp.abs().max(1),
+            ValueStyle::Boundary => BOUNDARY_TENTHS[rng.gen_range(0..BOUNDARY_TENTHS.len())],
+        }
+    }
+
+    /// Line `row` is replaced by a malformed one. The first lines are never malformed,
+    /// so all keys are in the output with Zipf popularity too.
+    #[inline]
+    fn malformed(&self, row: usize, keys: usize, rng: &mut GenRng) -> bool {
+        self.malformed > 0.0 && row >= keys && rng.gen::<f64>() < self.malformed
+    }
+}
+
+/// Standard normal value by the Box-Muller transform
+fn gaussian(rng: &mut GenRng) -> f32 {
+    // `1 - u` is in `(0, 1]`, so the logarithm is finite
+    let u = 1.0 - rng.gen::<f64>();
+    let v: f64 = rng.gen();
+    ((-2.0 * u.ln()).sqrt() * (TAU * v).cos()) as f32
+}
 
 #[derive(Debug)]
 struct WeatherStation {
@@ -30,80 +430,83 @@ impl WeatherStation {
 fn main() {
     let start = Instant::now();
 
-    let (stations, len, lines, bytes) = read_stations();
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("generator: {e}\n\n{USAGE}");
+            exit(2);
+        }
+    };
+    if args.help {
+        print!("{USAGE}");
+        return;
+    }
+
+    let (stations, len, lines, bytes) = read_stations(&args.stations).unwrap_or_else(|e| {
+        eprintln!("generator: {}: {e}", args.stations.display());
+        exit(1);
+    });
 
     verify(&stations);
     info(&stations);
+    if let Err(e) = args.validate(&stations) {
+        eprintln!("generator: {e}");
+        exit(2);
+    }
 
     println!("Unique keys: {}", stations.len());
     println!("{:?}", start.elapsed());
     println!("file length: {len}, lines: {lines}, bytes: {bytes}");
 
+    let seed = args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    println!("seed: {seed}");
+    let mut rng = GenRng::seed_from_u64(seed);
+
     println!("-->\tStart generate keys");
     let start_required_map = Instant::now();
-
-    let mut required_map: HashMap<&String, f32> = HashMap::with_capacity(REQUIRED_KEYS);
-    stations.iter().for_each(|s| {required_map.insert(&s.name, s.temp);});
-
-    let mut generated_map:HashMap<String, f32> = HashMap::with_capacity(REQUIRED_KEYS);
-
-    while (required_map.len() + generated_map.len()) < REQUIRED_KEYS {
-        let new = generate_key(&stations, &required_map, &generated_map);
-        match new {
-            Some((n, t)) => {generated_map.insert(n, t);}
-            None => {}
-        }
-    }
-    for (n, t) in generated_map.iter() {
-        required_map.insert(n, *t);
-    }
-    println!("\trequired map capacity: {}, len: {}", required_map.capacity(), required_map.len());
-    println!("\tgenerated map capacity: {}, len: {}", generated_map.capacity(), generated_map.len());
+    let keys = rename_keys(generate_keys(&stations, args.keys, &mut rng), args.names);
+    println!("\tkeys: {}, derived: {}", keys.len(), keys.len().saturating_sub(stations.len()));
     println!("-->\tEnd generate keys: {:?}", start_required_map.elapsed());
 
     println!("==>\tStart write data");
     let start_write_data = Instant::now();
-    let mut c: usize = 0;
-    let mut rng = rand::thread_rng();
 
-    let mut writer = BufWriter::with_capacity(BUFFER_SIZE, File::create(TARGET_FILE_NAME).unwrap());
-    println!("\tFile writer capacity: {}", writer.capacity());
-
-    while c < LINES_IN_TARGET {
-        required_map.iter().take_while(|_|  {
-            c += 1;
-            c < LINES_IN_TARGET + 1
-        }).for_each(|(station, t)| {
-            let temp: f32 = rng.gen_range((t - 20.0)..(t + 20.0));
-            writeln!(writer, "{station};{temp:.1}").unwrap();
-        });
+    let file = File::create(&args.output).unwrap_or_else(|e| {
+        eprintln!("generator: {}: {e}", args.output.display());
+        exit(1);
+    });
+    let mut writer = BufWriter::with_capacity(args.buffer_size, file);
+    let threads = args.threads.unwrap_or(available_parallelism().unwrap_or(NonZeroUsize::MIN));
+    println!("\tFile writer capacity: {}, threads: {threads}", writer.capacity());
+
+    let dist = Distribution::new(&args);
+    if let Err(e) = write_lines(&mut writer, &keys, args.lines, &dist, seed, threads) {
+        eprintln!("generator: {}: {e}", args.output.display());
+        exit(1);
     }
 
     match writer.flush() {
-        Ok(_) => {println!("\t{TARGET_FILE_NAME} saved!")}
+        Ok(_) => {println!("\t{} saved!", args.output.display())}
         Err(e) => {println!("\t{}", e)}
     }
     println!("==>\tEnd write data: {:?}", start_write_data.elapsed());
 }
 
-fn read_stations() -> (Vec<WeatherStation>, u64, usize, usize) {
+fn read_stations(path: &Path) -> std::io::Result<(Vec<WeatherStation>, u64, usize, usize)> {
     let mut stations = Vec::new();
 
-    let file = File::open(STATION_PATH).unwrap();
-    let len = file.metadata().unwrap().len();
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
     let reader = BufReader::new(file);
 
     let mut lines: usize = 0;
     let mut bytes: usize = 0;
 
     for line in reader.lines() {
-        let l = line.unwrap();
-        let v: Vec<&str> = l.split(';').collect();
-
-        let ws: WeatherStation = {
-            let n: String = v[0].to_string();
-            let t: f32 = v[1].parse().unwrap();
-            WeatherStation::new(n, t)
+        let l = line?;
+        let ws: WeatherStation = match l.rsplit_once(';').map(|(n, t)| (n, t.parse::<f32>())) {
+            Some((n, Ok(t))) => WeatherStation::new(n.to_string(), t),
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("line {}: expected `name;mean`: {l:?}", lines + 1))),
         };
 
         let len = l.len();
@@ -114,7 +517,7 @@ fn read_stations() -> (Vec<WeatherStation>, u64, usize, usize) {
         stations.push(ws);
     }
 
-    (stations, len, lines, bytes)
+    Ok((stations, len, lines, bytes))
 }
 
 fn verify(stations: &Vec<WeatherStation>) {
@@ -160,24 +563,208 @@ fn info(stations: &Vec<WeatherStation>) {
     println!("max len: {max_len}, line: {}\t{}", max_index + 1, stations.get(max_index).unwrap().name);
 }
 
-fn generate_key(stations: &Vec<WeatherStation>, required_map: &HashMap<&String, f32>, generated_map: &HashMap<String, f32>) -> Option<(String, f32)> {
-    let rand: usize = rand::thread_rng().gen_range(0..stations.len());
+/// Number of unique keys `generate_key` can derive: station names and their suffixed
+/// variants not longer than `STATION_NAME_MAX_LEN`
+fn max_keys(stations: &[WeatherStation]) -> usize {
+    let mut keys: HashSet<String> = stations.iter().map(|s| s.name.clone()).collect();
+    for s in stations {
+        for i in 1..=MAX_SUFFIX {
+            let key = format!("{}-{i}", s.name);
+            if key.len() <= STATION_NAME_MAX_LEN {
+                keys.insert(key);
+            }
+        }
+    }
+    keys.len()
+}
+
+/// `count` keys with mean temperatures in a reproducible order: the first stations of the file,
+/// then keys derived from random stations
+fn generate_keys(stations: &[WeatherStation], count: usize, rng: &mut GenRng) -> Vec<(String, f32)> {
+    let mut keys: Vec<(String, f32)> = stations.iter().take(count).map(|s| (s.name.clone(), s.temp)).collect();
+    let mut taken: HashSet<String> = keys.iter().map(|(n, _)| n.clone()).collect();
+
+    while keys.len() < count {
+        if let Some((n, t)) = generate_key(stations, &taken, rng) {
+            taken.insert(n.clone());
+            keys.push((n, t));
+        }
+    }
+    keys
+}
+
+/// Names of `keys` in the given style, mean temperatures are kept
+fn rename_keys(keys: Vec<(String, f32)>, style: NameStyle) -> Vec<(String, f32)> {
+    match style {
+        NameStyle::Stations => keys,
+        NameStyle::Long => keys.into_iter().enumerate().map(|(i, (name, t))| {
+            let suffix = format!("#{i}");
+            (fill(&name, STATION_NAME_MAX_LEN - suffix.len()) + &suffix, t)
+        }).collect(),
+        NameStyle::CommonPrefix => {
+            let width = keys.len().to_string().len();
+            let prefix = fill(&keys[0].0, STATION_NAME_MAX_LEN - width);
+            keys.into_iter().enumerate().map(|(i, (_, t))| (format!("{prefix}{i:0width$}"), t)).collect()
+        }
+    }
+}
+
+/// `len` bytes of `name` repeated, separated by spaces and padded by `.`
+fn fill(name: &str, len: usize) -> String {
+    let mut s = String::with_capacity(len);
+    for c in name.chars().chain([' ']).cycle() {
+        if s.len() + c.len_utf8() > len {
+            break;
+        }
+        s.push(c);
+    }
+    while s.len() < len {
+        s.push('.');
+    }
+    s
+}
+
+/// Write `lines` lines of `keys` drawn by `dist`.
+/// Blocks of `BLOCK_LINES` are formatted by `threads` workers and written in order.
+fn write_lines<W: Write>(writer: &mut W, keys: &[(String, f32)], lines: usize, dist: &Distribution, seed: u64, threads: NonZeroUsize) -> std::io::Result<()> {
+    let blocks = lines.div_ceil(BLOCK_LINES);
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::sync_channel::<(usize, Vec<u8>)>(threads.get());
+    // blocks written so far, `None` after a write error
+    let written = (Mutex::new(Some(0)), Condvar::new());
+    let ahead = BLOCKS_AHEAD * threads.get();
+
+    thread::scope(|s| {
+        for _ in 0..threads.get() {
+            let tx = tx.clone();
+            let next = &next;
+            let written = &written;
+            s.spawn(move || loop {
+                let block = next.fetch_add(1, Ordering::Relaxed);
+                if block >= blocks {
+                    break;
+                }
+                // a slow writer holds the workers back, so at most `ahead` blocks are in memory
+                let (lock, cvar) = written;
+                let done = cvar.wait_while(lock.lock().unwrap(), |w| w.is_some_and(|w| block >= w + ahead)).unwrap();
+                if done.is_none() {
+                    break;
+                }
+                drop(done);
+                let rows = block * BLOCK_LINES..((block + 1) * BLOCK_LINES).min(lines);
+                let mut buf = Vec::with_capacity(rows.len() * 24);
+                format_block(&mut buf, keys, rows, dist, &mut block_rng(seed, block));
+                // the receiver is dropped on a write error
+                if tx.send((block, buf)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // blocks are taken in order, so only a few finished ones wait for a slower worker
+        let (lock, cvar) = &written;
+        let mut pending = BTreeMap::new();
+        let mut next_write = 0;
+        for (block, buf) in rx {
+            pending.insert(block, buf);
+            while let Some(buf) = pending.remove(&next_write) {
+                let result = writer.write_all(&buf);
+                next_write += 1;
+                *lock.lock().unwrap() = result.is_ok().then_some(next_write);
+                cvar.notify_all();
+                result?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Random stream of a block, stream 0 is used for the keys
+fn block_rng(seed: u64, block: usize) -> GenRng {
+    let mut rng = GenRng::seed_from_u64(seed);
+    rng.set_stream(block as u64 + 1);
+    rng
+}
+
+/// Append lines `rows` of the output
+fn format_block(buf: &mut Vec<u8>, keys: &[(String, f32)], rows: Range<usize>, dist: &Distribution, rng: &mut GenRng) {
+    let mut next = rows.start % keys.len();
+    for row in rows {
+        let (station, mean) = &keys[dist.key(row, &mut next, keys.len(), rng)];
+        if dist.malformed(row, keys.len(), rng) {
+            push_malformed(buf, station, rng);
+            continue;
+        }
+        let temp = dist.temperature(row, *mean, rng);
+        buf.extend_from_slice(station.as_bytes());
+        buf.push(b';');
+        push_tenths(buf, temp);
+        buf.push(b'\n');
+    }
+}
+
+/// Append a line rejected by the parser, a kind of every `ParseError`.
+/// Errors of station names are found in strict mode only.
+fn push_malformed(buf: &mut Vec<u8>, name: &str, rng: &mut GenRng) {
+    let station = name.as_bytes();
+    match rng.gen_range(0..7) {
+        // missing delimiter
+        0 => buf.extend_from_slice(&[station, b" 12.3"].concat()),
+        // bad temperature
+        1 => buf.extend_from_slice(&[station, b";12.34"].concat()),
+        // trailing garbage
+        2 => buf.extend_from_slice(&[station, b";-4.5 C"].concat()),
+        // name too long
+        3 => buf.extend_from_slice(&[fill(name, STATION_NAME_MAX_LEN + 1).as_bytes(), b";1.0"].concat()),
+        // invalid UTF-8
+        4 => buf.extend_from_slice(&[&station[..station.len().min(8)], b"\xff\xfe;1.0"].concat()),
+        // empty name
+        5 => buf.extend_from_slice(b";1.0"),
+        // delimiter in name
+        _ => buf.extend_from_slice(&[station, b";x;1.0"].concat()),
+    }
+    buf.push(b'\n');
+}
+
+/// Degrees rounded to tenths
+fn tenths(t: f32) -> i16 {
+    (t * 10.0).round() as i16
+}
+
+/// Append tenths `v` as degrees with one decimal, e.g. `-12.3`
+#[inline]
+fn push_tenths(buf: &mut Vec<u8>, v: i16) {
+    if v < 0 {
+        buf.push(b'-');
+    }
+    let v = v.unsigned_abs();
+    if v >= 100 {
+        buf.push(b'0' + (v / 100) as u8);
+    }
+    buf.extend_from_slice(&[b'0' + (v / 10 % 10) as u8, b'.', b'0' + (v % 10) as u8]);
+}
+
+fn generate_key(stations: &[WeatherStation], taken: &HashSet<String>, rng: &mut GenRng) -> Option<(String, f32)> {
+    let rand: usize = rng.gen_range(0..stations.len());
     let station = stations.get(rand).unwrap();
     let name = &station.name;
     let temp = station.temp;
     let mut i = 0;
     let mut str = name.clone();
-    while (required_map.contains_key(&str) || generated_map.contains_key(&str)) && i < 10 {
+    while taken.contains(&str) && i <= MAX_SUFFIX {
         i += 1;
         str = format!("{name}-{i}");
     }
-    if i == 10 || str.len() > STATION_NAME_MAX_LEN { None } else { Some((str, temp)) }
+    if i > MAX_SUFFIX || str.len() > STATION_NAME_MAX_LEN { None } else { Some((str, temp)) }
 }
 
 #[cfg(test)]
 mod test {
+    use std::num::NonZeroUsize;
+    use std::path::PathBuf;
     use rand::Rng;
-    use crate::WeatherStation;
+    use crate::{Args, NameStyle, Popularity, TemperatureDist, ValueStyle, WeatherStation, ZIPF_EXPONENT};
 
     #[test]
     fn test_increase_string() {
@@ -227,4 +814,185 @@ bad;234234;234234
         let s = WeatherStation::new("test".to_string(), 3.9);
         println!("{s:?}");
     }
+
+    #[test]
+    fn test_args_parse() {
+        fn parse(s: &str) -> Result<Args, String> {
+            Args::parse(s.split_whitespace().map(String::from))
+        }
+
+        assert_eq!(Args::default(), parse("").unwrap());
+        let a = parse("-n 1000 --keys=300 --stations s.txt -o out.txt --spread 5.5 --buffer-size 4096 -t 3").unwrap();
+        assert_eq!((1000, 300, 5.5, 4096), (a.lines, a.keys, a.spread, a.buffer_size));
+        assert_eq!(NonZeroUsize::new(3), a.threads);
+        let d = parse("--popularity zipf:1.5 --temperature gaussian --sigma 3 --seasonal 15").unwrap();
+        assert_eq!((Popularity::Zipf(1.5), TemperatureDist::Gaussian, 3.0, 15.0), (d.popularity, d.temperature, d.sigma, d.seasonal));
+        assert_eq!(Popularity::Zipf(ZIPF_EXPONENT), parse("--popularity zipf").unwrap().popularity);
+        let p = parse("--preset long-names,max-keys,negative,malformed --malformed 0.5").unwrap();
+        assert_eq!((NameStyle::Long, ValueStyle::Negative, 0.5, 10_000), (p.names, p.values, p.malformed, p.keys));
+        // a preset overrides the options before it
+        let p = parse("--names common-prefix --values boundary --preset negative").unwrap();
+        assert_eq!((NameStyle::CommonPrefix, ValueStyle::Negative), (p.names, p.values));
+        assert_eq!((PathBuf::from("s.txt"), PathBuf::from("out.txt")), (a.stations, a.output));
+        assert!(parse("-h").unwrap().help);
+
+        for bad in ["-k 0", "-n 10 -k 11", "--spread -1", "--spread 200", "--buffer-size 0", "-t 0", "--popularity zipf:0", "--popularity zipf:x", "--popularity hot", "--temperature normal", "--sigma -1", "--seasonal 300", "--names short", "--values hot", "--malformed 1.5", "--preset worst", "-n", "-n x", "--unknown"] {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let long = "x".repeat(99);
+        let stations = vec![
+            WeatherStation::new("a".to_string(), 1.0),
+            WeatherStation::new("a-1".to_string(), 2.0),
+            WeatherStation::new(long, 3.0),
+        ];
+        // a, a-1..a-9, a-1-1..a-1-9 and the long name without suffixes
+        assert_eq!(10 + 9 + 1, crate::max_keys(&stations));
+
+        let a = Args { keys: 21, ..Args::default() };
+        assert!(a.validate(&stations).is_err());
+        assert!(Args { keys: 20, ..a }.validate(&stations).is_ok());
+    }
+
+    /// Generate measurements from `stations2.txt` in memory by seeded `args`
+    fn generate(args: &Args) -> Vec<u8> {
+        use rand::SeedableRng;
+
+        let (stations, _, _, _) = crate::read_stations(std::path::Path::new(crate::STATION_PATH)).unwrap();
+        let seed = args.seed.unwrap();
+        let mut rng = crate::GenRng::seed_from_u64(seed);
+        let keys = crate::rename_keys(crate::generate_keys(&stations, args.keys, &mut rng), args.names);
+        let mut buf = Vec::new();
+        let threads = args.threads.unwrap_or(NonZeroUsize::MIN);
+        crate::write_lines(&mut buf, &keys, args.lines, &crate::Distribution::new(args), seed, threads).unwrap();
+        buf
+    }
+
+    /// Lines of the generated data as `(name, tenths)`
+    fn parse_lines(data: &[u8]) -> Vec<(&str, i32)> {
+        std::str::from_utf8(data).unwrap().lines().map(|l| {
+            let (name, t) = l.rsplit_once(';').unwrap();
+            (name, (t.parse::<f64>().unwrap() * 10.0).round() as i32)
+        }).collect()
+    }
+
+    #[test]
+    fn test_seeded_output() {
+        let lines = 3 * crate::BLOCK_LINES + 123;
+        let args = Args { lines, keys: 3000, seed: Some(2024), threads: NonZeroUsize::new(3), ..Args::default() };
+        let a = generate(&args);
+        assert_eq!(lines, a.iter().filter(|&&b| b == b'\n').count());
+        assert_eq!(a, generate(&Args { threads: None, ..args.clone() }));
+        assert_ne!(a, generate(&Args { seed: Some(2025), ..args.clone() }));
+
+        // the stored checksum changes only if the output format or the algorithm does
+        assert_eq!(0x799e_b61e, crc32fast::hash(&a), "{:#010x}", crc32fast::hash(&a));
+    }
+
+    #[test]
+    fn test_write_error() {
+        /// Writer failing when more than its capacity is written
+        struct Full(usize);
+
+        impl std::io::Write for Full {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 = self.0.checked_sub(buf.len()).ok_or_else(|| std::io::Error::other("disk full"))?;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // the workers waiting for the writer stop too
+        let args = Args { lines: 50 * crate::BLOCK_LINES, ..Args::default() };
+        let keys = vec![("a".to_string(), 10.0)];
+        let e = crate::write_lines(&mut Full(1 << 20), &keys, args.lines, &crate::Distribution::new(&args), 1, NonZeroUsize::new(4).unwrap()).unwrap_err();
+        assert_eq!("disk full", e.to_string());
+    }
+
+    #[test]
+    fn test_distributions() {
+        use std::collections::HashMap;
+
+        let args = Args { lines: 100_000, keys: 1000, seed: Some(7), threads: NonZeroUsize::new(2), ..Args::default() };
+
+        // Zipf: all keys are written, the first one is the most frequent
+        let zipf = generate(&Args { popularity: Popularity::Zipf(1.2), ..args.clone() });
+        let lines = parse_lines(&zipf);
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        lines.iter().for_each(|(name, _)| *counts.entry(name).or_default() += 1);
+        assert_eq!(args.keys, counts.len());
+        let max = counts.values().max().unwrap();
+        assert_eq!(max, &counts[lines[0].0]);
+        assert!(*max > args.lines / 10, "{max}");
+
+        // Gaussian around the station means: lines of one key have the standard deviation sigma
+        let gaussian = generate(&Args { temperature: TemperatureDist::Gaussian, sigma: 5.0, ..args.clone() });
+        let lines = parse_lines(&gaussian);
+        let first: Vec<f64> = lines.iter().filter(|(name, _)| *name == lines[0].0).map(|&(_, t)| t as f64).collect();
+        let mean = first.iter().sum::<f64>() / first.len() as f64;
+        let sd = (first.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / first.len() as f64).sqrt();
+        assert!((sd - 50.0).abs() < 10.0, "{sd}");
+
+        // seasonal drift: the first quarter of the file is warmer than the third one
+        let seasonal = generate(&Args { seasonal: 30.0, ..args.clone() });
+        let lines = parse_lines(&seasonal);
+        let quarter = |q: usize| lines[q * lines.len() / 4..(q + 1) * lines.len() / 4].iter().map(|&(_, t)| t as f64).sum::<f64>();
+        assert!(quarter(0) - quarter(2) > 300.0 * (lines.len() / 4) as f64);
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        use std::collections::HashSet;
+        use one_brc::driver::Driver;
+        use one_brc::stats::ParseError;
+        use one_brc::{TemperStat, STATION_NAME_MAX_LEN};
+
+        let args = Args { lines: 20_000, keys: 2000, seed: Some(11), ..Args::default() };
+
+        for names in [NameStyle::Long, NameStyle::CommonPrefix] {
+            let data = generate(&Args { names, ..args.clone() });
+            let lines = parse_lines(&data);
+            let keys: HashSet<&str> = lines.iter().map(|(name, _)| *name).collect();
+            assert_eq!(args.keys, keys.len());
+            assert!(keys.iter().all(|k| k.len() == STATION_NAME_MAX_LEN), "{names:?}");
+            if names == NameStyle::CommonPrefix {
+                // consecutive keys differ in the last byte only
+                assert_eq!(lines[0].0[..STATION_NAME_MAX_LEN - 1], lines[1].0[..STATION_NAME_MAX_LEN - 1]);
+            }
+        }
+
+        let negative = generate(&Args { values: ValueStyle::Negative, ..args.clone() });
+        assert!(parse_lines(&negative).iter().all(|&(_, t)| t < 0));
+
+        let boundary = generate(&Args { values: ValueStyle::Boundary, ..args.clone() });
+        let values: HashSet<i32> = parse_lines(&boundary).iter().map(|&(_, t)| t).collect();
+        assert_eq!(crate::BOUNDARY_TENTHS.iter().map(|&t| t as i32).collect::<HashSet<_>>(), values);
+
+        // malformed lines are rejected by the parser for every reason, all keys are still valid
+        let malformed = generate(&Args { malformed: 0.2, ..args.clone() });
+        let run = Driver::new().strict(true).process_slice::<TemperStat>(&malformed).unwrap();
+        assert_eq!(args.lines, run.stats.lines);
+        assert_eq!(args.keys, run.result.iter().count());
+        let rejected = run.stats.rejected() as f64 / args.lines as f64;
+        assert!((rejected - 0.2 * 0.9).abs() < 0.02, "{rejected}");
+        for e in ParseError::ALL {
+            assert!(run.stats.errors(e) > 0, "{e:?}");
+        }
+    }
+
+    #[test]
+    fn test_push_tenths() {
+        let mut buf = Vec::new();
+        for v in -999..=999 {
+            buf.clear();
+            crate::push_tenths(&mut buf, v);
+            assert_eq!(format!("{:.1}", v as f64 / 10.0), String::from_utf8_lossy(&buf), "{v}");
+        }
+    }
 }
@@ -0,0 +1,365 @@
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::{FILE_PATH, ParseOptions};
+use crate::backend::Backend;
+use crate::csv::{Column, CsvOptions};
+use crate::histogram::Quantile;
+use crate::parallel::CHUNK_SIZE;
+use crate::result::ExtraStats;
+use crate::stats::ErrorPolicy;
+use crate::stream::STREAM_BUFFER_SIZE;
+
+pub const USAGE: &str = "\
+Usage: one-brc [OPTIONS] [FILE|GLOB]...
+       one-brc merge [OPTIONS] SNAPSHOT...
+
+Aggregate min/mean/max temperature per station from 1BRC measurement files.
+Without FILE the default measurements file is processed, `-` reads stdin.
+Quoted patterns like 'data/*.txt' are expanded, all files are processed by one worker pool.
+Gzip and zstd compressed inputs are decompressed on the fly.
+The merge command combines snapshots written with `--format snapshot`, only the output
+options and `--stats` apply to it.
+
+Options:
+  -t, --threads <N>        Number of worker threads [default: available parallelism]
+      --backend <NAME>     Force parsing backend: scalar, sse2, avx2, neon [default: detected]
+      --chunk-size <BYTES> Bytes of a file taken by a worker at once [default: 4194304]
+      --buffer-size <BYTES>
+                           Read buffer size for stdin and pipes [default: 16777216]
+  -o, --output <PATH>      Write the result to PATH instead of stdout
+  -f, --format <FORMAT>    Output format: 1brc, json, json-object, csv, tsv, snapshot [default: 1brc]
+      --columns <LIST>     CSV/TSV columns: station,min,mean,max,count,sum,variance,stddev
+                           and quantiles like median,p95,p99.9 [default: station,min,mean,max,count,sum]
+      --variance           Add variance and standard deviation to the output
+      --percentiles        Add exact median, p5, p95 and p99 to the output
+      --quantiles <LIST>   Add exact quantiles to the output, e.g. median,p90,0.999
+      --no-header          Don't write CSV/TSV header row
+      --precision <N>      CSV/TSV digits after decimal point [default: 1]
+      --per-file           Write the result of every file before the combined one
+  -s, --stats              Print line and error counters to stderr
+      --on-error <ACTION>  Action when parse errors exceed the limits: ignore, warn, fail [default: warn]
+      --max-errors <N>     Maximum allowed rejected lines [default: 0]
+      --max-error-ratio <R>
+                           Maximum allowed rejected to all lines ratio, e.g. 0.001
+      --strict             Reject lines and results breaking the 1BRC input constraints
+      --diagnostics <N>    Print up to N rejected lines per file to stderr [default: 0]
+  -h, --help               Print this help
+";
+
+/// Options of the input processing, rejected by the merge command
+const AGGREGATE_ONLY: &[&str] = &[
+    "-t", "--threads", "--backend", "--chunk-size", "--buffer-size", "--per-file",
+    "--on-error", "--max-errors", "--max-error-ratio", "--strict", "--diagnostics",
+];
+
+/// Result output format
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// `{Abha=5.0/18.0/27.4, ...}` as required by the challenge
+    #[default]
+    Brc,
+    /// JSON array of station objects
+    Json,
+    /// JSON object keyed by station
+    JsonObject,
+    Csv,
+    Tsv,
+    /// Binary snapshot with exact statistics, can be merged later
+    Snapshot,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1brc" | "brc" => Ok(OutputFormat::Brc),
+            "json" => Ok(OutputFormat::Json),
+            "json-object" => Ok(OutputFormat::JsonObject),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "snapshot" => Ok(OutputFormat::Snapshot),
+            _ => Err(format!("unknown output format `{s}`")),
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Brc => write!(f, "1brc"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::JsonObject => write!(f, "json-object"),
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Tsv => write!(f, "tsv"),
+            OutputFormat::Snapshot => write!(f, "snapshot"),
+        }
+    }
+}
+
+/// `one-brc` command
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Command {
+    /// Process measurement files
+    #[default]
+    Aggregate,
+    /// Combine result snapshots
+    Merge,
+}
+
+/// Command line arguments of the `one-brc` binary
+#[derive(Clone, Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub inputs: Vec<PathBuf>,
+    pub threads: Option<NonZeroUsize>,
+    /// Parsing backend overriding the detected one and `ONE_BRC_BACKEND`
+    pub backend: Option<Backend>,
+    /// Chunk size of memory mapped files
+    pub chunk_size: NonZeroUsize,
+    /// Read buffer size of not mmappable inputs
+    pub buffer_size: usize,
+    pub output: Option<PathBuf>,
+    pub format: OutputFormat,
+    /// CSV/TSV options, the delimiter is set by `format`
+    pub csv: CsvOptions,
+    /// Optional statistics, added to CSV/TSV columns too. Quantiles turn on histograms.
+    pub extra: ExtraStats,
+    /// Write per-file results before the combined result
+    pub per_file: bool,
+    pub stats: bool,
+    pub errors: ErrorPolicy,
+    /// Enforce the 1BRC input constraints
+    pub strict: bool,
+    /// Rejected line samples kept per file
+    pub diagnostics: usize,
+    pub help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            command: Command::default(),
+            inputs: Vec::new(),
+            threads: None,
+            backend: None,
+            chunk_size: NonZeroUsize::new(CHUNK_SIZE).unwrap(),
+            buffer_size: STREAM_BUFFER_SIZE,
+            output: None,
+            format: OutputFormat::default(),
+            csv: CsvOptions::default(),
+            extra: ExtraStats::default(),
+            per_file: false,
+            stats: false,
+            errors: ErrorPolicy::default(),
+            strict: false,
+            diagnostics: 0,
+            help: false,
+        }
+    }
+}
+
+impl Args {
+    /// Parse arguments without the program name.
+    /// If no input is given, `FILE_PATH` is used, the merge command requires inputs.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut result = Args::default();
+        let mut args = args.into_iter().peekable();
+        let mut only_inputs = false;
+
+        if args.peek().is_some_and(|a| a == "merge") {
+            args.next();
+            result.command = Command::Merge;
+        }
+
+        while let Some(arg) = args.next() {
+            if only_inputs || arg == "-" || !arg.starts_with('-') {
+                result.inputs.push(PathBuf::from(arg));
+                continue;
+            }
+
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+                _ => (arg.clone(), None),
+            };
+
+            let mut value = |name: &str| -> Result<String, String> {
+                inline.clone().or_else(|| args.next()).ok_or(format!("missing value for `{name}`"))
+            };
+
+            if result.command == Command::Merge && AGGREGATE_ONLY.contains(&flag.as_str()) {
+                return Err(format!("`{flag}` can't be used with the merge command"));
+            }
+            match flag.as_str() {
+                "--" => only_inputs = true,
+                "-h" | "--help" => result.help = true,
+                "-s" | "--stats" => result.stats = true,
+                "--per-file" => result.per_file = true,
+                "-t" | "--threads" => result.threads = Some(parse_value(&flag, &value(&flag)?)?),
+                "--backend" => result.backend = Some(value(&flag)?.parse()?),
+                "--chunk-size" => result.chunk_size = parse_value(&flag, &value(&flag)?)?,
+                "--buffer-size" => result.buffer_size = parse_value(&flag, &value(&flag)?)?,
+                "-o" | "--output" => result.output = Some(PathBuf::from(value(&flag)?)),
+                "-f" | "--format" => result.format = value(&flag)?.parse()?,
+                "--columns" => result.csv.columns = Column::parse_list(&value(&flag)?)?,
+                "--variance" => result.extra.variance = true,
+                "--percentiles" => result.extra.quantiles.extend(Quantile::PERCENTILES),
+                "--quantiles" => result.extra.quantiles.extend(parse_quantiles(&value(&flag)?)?),
+                "--no-header" => result.csv.header = false,
+                "--precision" => result.csv.precision = parse_value(&flag, &value(&flag)?)?,
+                "--strict" => result.strict = true,
+                "--on-error" => result.errors.action = value(&flag)?.parse()?,
+                "--max-errors" => result.errors.max_errors = Some(parse_value(&flag, &value(&flag)?)?),
+                "--diagnostics" => result.diagnostics = parse_value(&flag, &value(&flag)?)?,
+                "--max-error-ratio" => result.errors.max_ratio = Some(parse_ratio(&flag, &value(&flag)?)?),
+                _ => return Err(format!("unknown option `{arg}`")),
+            }
+        }
+
+        if result.format == OutputFormat::Tsv {
+            result.csv.delimiter = b'\t';
+        }
+        if matches!(result.format, OutputFormat::Csv | OutputFormat::Tsv) {
+            for c in result.csv.columns.iter() {
+                if let Column::Quantile(q) = c { result.extra.quantiles.push(*q); }
+            }
+        }
+        let mut quantiles = Vec::new();
+        result.extra.quantiles.retain(|q| !quantiles.contains(q) && { quantiles.push(*q); true });
+        if result.extra.variance {
+            for c in [Column::Variance, Column::StdDev] {
+                if !result.csv.columns.contains(&c) { result.csv.columns.push(c); }
+            }
+        }
+        for q in result.extra.quantiles.iter() {
+            let c = Column::Quantile(*q);
+            if !result.csv.columns.contains(&c) { result.csv.columns.push(c); }
+        }
+
+        if result.per_file && result.format == OutputFormat::Snapshot {
+            return Err("`--per-file` can't be used with the snapshot format".to_string());
+        }
+
+        if result.inputs.is_empty() {
+            match result.command {
+                Command::Aggregate => result.inputs.push(PathBuf::from(FILE_PATH)),
+                Command::Merge if !result.help => return Err("merge requires at least one snapshot".to_string()),
+                Command::Merge => {}
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+impl Args {
+    /// Line parsing options of the arguments
+    pub fn parse_options(&self) -> ParseOptions {
+        let variance = self.extra.variance || self.csv.columns.iter().any(|c| matches!(c, Column::Variance | Column::StdDev));
+        ParseOptions { strict: self.strict, histogram: self.extra.histogram(), variance }
+    }
+}
+
+/// Parse comma separated quantile list, e.g. `median,p95,0.999`
+fn parse_quantiles(s: &str) -> Result<Vec<Quantile>, String> {
+    s.split(',').map(|q| q.trim().parse()).collect()
+}
+
+/// Parse option value with the option name in the error message
+pub fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value `{value}` for `{name}`"))
+}
+
+fn parse_ratio(name: &str, value: &str) -> Result<f64, String> {
+    let r: f64 = parse_value(name, value)?;
+    if (0.0..=1.0).contains(&r) { Ok(r) } else { Err(format!("`{name}` must be between 0 and 1")) }
+}
+
+#[test]
+fn test_args_parse() {
+    use crate::stats::ErrorAction;
+
+    fn parse(s: &str) -> Result<Args, String> {
+        Args::parse(s.split_whitespace().map(String::from))
+    }
+
+    let a = parse("").unwrap();
+    assert_eq!(vec![PathBuf::from(FILE_PATH)], a.inputs);
+    assert_eq!(None, a.threads);
+    assert!(!a.stats);
+
+    let a = parse("-t 4 --output out.txt --stats --format=1brc a.txt b.txt").unwrap();
+    assert_eq!(vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")], a.inputs);
+    assert_eq!(NonZeroUsize::new(4), a.threads);
+    assert_eq!(Some(PathBuf::from("out.txt")), a.output);
+    assert_eq!(OutputFormat::Brc, a.format);
+    assert!(a.stats);
+
+    assert!(!a.strict);
+    assert!(parse("--strict").unwrap().strict);
+
+    let a = parse("merge -f csv a.snap b.snap").unwrap();
+    assert_eq!(Command::Merge, a.command);
+    assert_eq!(vec![PathBuf::from("a.snap"), PathBuf::from("b.snap")], a.inputs);
+    assert_eq!(Command::Aggregate, parse("./merge").unwrap().command);
+    assert!(parse("merge").is_err());
+    assert!(parse("merge --stats --variance -o out.txt a.snap").is_ok());
+    for option in ["--strict", "-t 2", "--threads=2", "--per-file", "--on-error fail", "--backend scalar", "--diagnostics 5"] {
+        let e = parse(&format!("merge {option} a.snap")).unwrap_err();
+        assert!(e.ends_with("can't be used with the merge command"), "{option}: {e}");
+    }
+
+    let a = parse("--buffer-size 1024 -").unwrap();
+    assert_eq!(1024, a.buffer_size);
+    assert_eq!(CHUNK_SIZE, a.chunk_size.get());
+    assert_eq!(65536, parse("--chunk-size 65536").unwrap().chunk_size.get());
+    assert!(parse("--chunk-size 0").is_err());
+    assert_eq!(Some(Backend::Scalar), parse("--backend scalar").unwrap().backend);
+    assert!(parse("--backend sse").is_err());
+    assert_eq!(vec![PathBuf::from("-")], a.inputs);
+
+    let a = parse("-- -t").unwrap();
+    assert_eq!(vec![PathBuf::from("-t")], a.inputs);
+
+    assert!(parse("-t 0").is_err());
+    assert!(parse("-t").is_err());
+    assert!(parse("--format xml").is_err());
+    assert_eq!(OutputFormat::JsonObject, parse("-f json-object").unwrap().format);
+
+    let a = parse("-f tsv --columns station,mean --no-header --precision 2").unwrap();
+    assert_eq!(b'\t', a.csv.delimiter);
+    assert_eq!(vec![Column::Station, Column::Mean], a.csv.columns);
+    assert!(!a.csv.header);
+    assert_eq!(2, a.csv.precision);
+    assert_eq!(CsvOptions::default(), parse("-f csv").unwrap().csv);
+    assert!(parse("--columns station,mode").is_err());
+    let a = parse("--variance --columns station,stddev").unwrap();
+    assert!(a.extra.variance);
+    assert_eq!(vec![Column::Station, Column::StdDev, Column::Variance], a.csv.columns);
+    assert!(!a.parse_options().histogram);
+
+    let median = Column::Quantile(Quantile::MEDIAN);
+    let a = parse("--percentiles --quantiles p99,0.999 --columns station,p99").unwrap();
+    assert_eq!(["median", "p5", "p95", "p99", "p99.9"], a.extra.quantiles.iter().map(|q| q.to_string()).collect::<Vec<_>>().as_slice());
+    assert_eq!(Column::Station, a.csv.columns[0]);
+    assert_eq!(6, a.csv.columns.len());
+    assert!(a.parse_options().histogram);
+    let a = parse("-f csv --columns station,median").unwrap();
+    assert_eq!(vec![Quantile::MEDIAN], a.extra.quantiles);
+    assert_eq!(vec![Column::Station, median], a.csv.columns);
+    assert!(parse("--quantiles p200").is_err());
+    assert!(parse("--unknown").is_err());
+
+    let a = parse("--on-error fail --max-errors 10 --max-error-ratio 0.01 --diagnostics 5").unwrap();
+    assert_eq!(5, a.diagnostics);
+    assert_eq!(ErrorAction::Fail, a.errors.action);
+    assert_eq!(Some(10), a.errors.max_errors);
+    assert_eq!(Some(0.01), a.errors.max_ratio);
+    assert!(parse("--on-error panic").is_err());
+    assert!(parse("--max-error-ratio 2").is_err());
+
+    assert!(parse("--per-file data/*.txt").unwrap().per_file);
+    assert!(parse("--per-file -f snapshot").is_err());
+}
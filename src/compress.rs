@@ -0,0 +1,308 @@
+//! Transparent decompression of gzip and zstd measurement files.
+//!
+//! Files of several independent zstd frames or BGZF gzip members (`bgzip`) are decoded
+//! in parallel: the compressed data is split at frame boundaries into chunks, every worker
+//! decodes its chunks by buffers like `process_stream`, so a huge frame is never decoded
+//! into memory at once. The lines cut by chunk boundaries are stitched at the end.
+//! Other compressed inputs are decoded by one thread while the lines are parsed by the
+//! `process_stream` workers.
+use std::io::{BufRead, BufReader, Read, Result};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+use flate2::bufread::MultiGzDecoder;
+use memchr::{memchr, memrchr};
+use crate::aggregator::Aggregator;
+use crate::diagnostics::Diagnostics;
+use crate::{process_block_stats, ParseOptions};
+use crate::result::TemperStatResult;
+use crate::stats::RunStats;
+use crate::stream::{process_stream, read_buffers};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Minimum compressed size of a chunk decoded by one worker
+pub const COMPRESSED_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect compression by magic bytes at the data begin
+    pub fn detect(head: &[u8]) -> Self {
+        if head.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Wrap reader into sequential decoder, multi-member gzip and multi-frame zstd are supported
+    pub fn decoder<'a, R: BufRead + Send + 'a>(&self, reader: R) -> Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        })
+    }
+
+    /// Independent frames of compressed data, `None` if boundaries can't be found without decoding
+    pub fn frames<'a>(&self, data: &'a [u8]) -> Option<Vec<&'a [u8]>> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => bgzf_members(data),
+            Compression::Zstd => zstd_frames(data),
+        }
+    }
+}
+
+/// Stream reader decompressing the input if it starts with gzip or zstd magic
+pub fn auto_decoder<'a, R: Read + Send + 'a>(reader: R) -> Result<Box<dyn Read + Send + 'a>> {
+    let mut reader = BufReader::with_capacity(1024 * 1024, reader);
+    let compression = Compression::detect(reader.fill_buf()?);
+    compression.decoder(reader)
+}
+
+fn zstd_frames(data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut frames = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let size = zstd::zstd_safe::find_frame_compressed_size(rest).ok()?;
+        let (frame, tail) = rest.split_at(size);
+        frames.push(frame);
+        rest = tail;
+    }
+    Some(frames)
+}
+
+/// BGZF members, every member header has `BC` extra subfield with the member size
+fn bgzf_members(data: &[u8]) -> Option<Vec<&[u8]>> {
+    const FEXTRA: u8 = 0x04;
+
+    let mut members = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < 12 || !rest.starts_with(&GZIP_MAGIC) || rest[3] & FEXTRA == 0 {
+            return None;
+        }
+        let xlen = u16::from_le_bytes([rest[10], rest[11]]) as usize;
+        let mut extra = rest.get(12..12 + xlen)?;
+        let mut size = None;
+        while extra.len() >= 4 {
+            let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+            if extra[0] == b'B' && extra[1] == b'C' && len == 2 && extra.len() >= 6 {
+                size = Some(u16::from_le_bytes([extra[4], extra[5]]) as usize + 1);
+            }
+            extra = extra.get(4 + len..)?;
+        }
+        let size = size?;
+        if size > rest.len() {
+            return None;
+        }
+        let (member, tail) = rest.split_at(size);
+        members.push(member);
+        rest = tail;
+    }
+    Some(members)
+}
+
+/// Join consecutive frames into chunks of at least `size` bytes, frames are adjacent in `data`
+fn chunks<'a>(data: &'a [u8], frames: &[&[u8]], size: usize) -> Vec<&'a [u8]> {
+    let mut chunks = Vec::new();
+    let mut begin = 0;
+    let mut end = 0;
+    for f in frames {
+        end += f.len();
+        if end - begin >= size {
+            chunks.push(&data[begin..end]);
+            begin = end;
+        }
+    }
+    if end > begin {
+        chunks.push(&data[begin..end]);
+    }
+    chunks
+}
+
+/// Line fragments of a decoded chunk which can't be parsed without the neighbour chunks
+#[derive(Default)]
+struct Fragments {
+    /// Bytes up to and including the first `\n`, or all bytes without `\n`
+    head: Vec<u8>,
+    /// Bytes after the last `\n`
+    tail: Vec<u8>,
+    has_line_end: bool,
+}
+
+/// Process gzip or zstd compressed data, frames are decoded in parallel if possible
+pub fn process_compressed<A: Aggregator + Send>(data: &[u8], compression: Compression, threads: NonZeroUsize, buffer_size: usize, options: ParseOptions) -> Result<(TemperStatResult<'static, A>, RunStats)> {
+    let frames = match compression.frames(data) {
+        Some(frames) if frames.len() > 1 => frames,
+        _ => return process_stream(compression.decoder(data)?, threads, buffer_size, options),
+    };
+    process_chunks(&chunks(data, &frames, COMPRESSED_CHUNK_SIZE), compression, threads, buffer_size, options)
+}
+
+/// Decode and parse chunks of whole frames in parallel, then parse the lines cut by chunk boundaries.
+/// Every chunk is decoded by buffers of `buffer_size` bytes, a line which doesn't fit is a `StreamError`.
+fn process_chunks<A: Aggregator + Send>(chunks: &[&[u8]], compression: Compression, threads: NonZeroUsize, buffer_size: usize, options: ParseOptions) -> Result<(TemperStatResult<'static, A>, RunStats)> {
+    let buffer_size = buffer_size.max(crate::LINE_MAX_LEN * 2);
+    let next = AtomicUsize::new(0);
+    let fragments: Mutex<Vec<Fragments>> = Mutex::new((0..chunks.len()).map(|_| Fragments::default()).collect());
+
+    let workers = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads.get().min(chunks.len())).map(|_| s.spawn(|| -> Result<(TemperStatResult<'static, A>, RunStats)> {
+            let mut result = TemperStatResult::new();
+            let mut stats = RunStats::new();
+            let (free_tx, free_rx) = channel::<Vec<u8>>();
+
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&chunk) = chunks.get(i) else { break };
+
+                // all buffers but the last one end with `\n`
+                let mut f = Fragments::default();
+                let mut first = true;
+                read_buffers(&mut compression.decoder(chunk)?, buffer_size, &free_rx, |buf| {
+                    let mut lines = &buf[..];
+                    if std::mem::take(&mut first) {
+                        let Some(end) = memchr(b'\n', lines) else {
+                            f.head = buf;
+                            return true;
+                        };
+                        f.has_line_end = true;
+                        f.head.extend_from_slice(&lines[..=end]);
+                        lines = &lines[end + 1..];
+                    }
+                    let end = memrchr(b'\n', lines).map_or(0, |last| last + 1);
+                    f.tail.clear();
+                    f.tail.extend_from_slice(&lines[end..]);
+                    let (map, s) = process_block_stats(&lines[..end], 7000, options, &mut Diagnostics::default());
+                    result.aggregate_owned(&map);
+                    stats.merge(&s);
+                    drop(map);
+                    let _ = free_tx.send(buf);
+                    true
+                })?;
+                fragments.lock().unwrap()[i] = f;
+            }
+            Ok((result, stats))
+        })).collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    let mut result = TemperStatResult::new();
+    let mut stats = RunStats::new();
+    for w in workers {
+        let (r, s) = w?;
+        result.merge(&r);
+        stats.merge(&s);
+    }
+
+    // lines cut by chunk boundaries
+    let mut lines: Vec<u8> = Vec::new();
+    let mut carry: Vec<u8> = Vec::new();
+    for f in fragments.into_inner().unwrap() {
+        if f.has_line_end {
+            lines.extend_from_slice(&carry);
+            lines.extend_from_slice(&f.head);
+            carry = f.tail;
+        } else {
+            carry.extend_from_slice(&f.head);
+        }
+    }
+    lines.extend_from_slice(&carry);
+    let (map, s) = process_block_stats(&lines, 100, options, &mut Diagnostics::default());
+    result.aggregate_owned(&map);
+    stats.merge(&s);
+
+    Ok((result, stats))
+}
+
+#[test]
+fn test_process_compressed() {
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use crate::{process_block, TemperStat};
+    use crate::test::TEST_STR_100;
+
+    let data = TEST_STR_100.repeat(20);
+    let (m, lines, _) = process_block::<TemperStat>(data.as_bytes(), 100);
+    let mut expected = TemperStatResult::new();
+    expected.aggregate(&m);
+
+    // frames of 333 bytes cut lines in the middle
+    let parts: Vec<&[u8]> = data.as_bytes().chunks(333).collect();
+
+    let mut zstd_data = Vec::new();
+    for p in parts.iter() {
+        zstd_data.extend(zstd::bulk::compress(p, 1).unwrap());
+    }
+
+    let mut gzip_data = Vec::new();
+    for p in parts.iter() {
+        let mut e = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        e.write_all(p).unwrap();
+        gzip_data.extend(e.finish().unwrap());
+    }
+
+    assert_eq!(Compression::Zstd, Compression::detect(&zstd_data));
+    assert_eq!(Compression::Gzip, Compression::detect(&gzip_data));
+    assert_eq!(Compression::None, Compression::detect(data.as_bytes()));
+    assert_eq!(parts.len(), Compression::Zstd.frames(&zstd_data).unwrap().len());
+    // plain multi-member gzip has no member sizes
+    assert_eq!(None, Compression::Gzip.frames(&gzip_data));
+
+    for (compressed, compression) in [(&zstd_data, Compression::Zstd), (&gzip_data, Compression::Gzip)] {
+        for threads in [1, 4] {
+            let (r, stats) = process_compressed::<TemperStat>(compressed, compression, NonZeroUsize::new(threads).unwrap(), 1024, ParseOptions::default()).unwrap();
+            assert_eq!(expected.to_string(), r.to_string());
+            assert_eq!(lines, stats.lines);
+        }
+
+        let mut decoded = String::new();
+        auto_decoder(compressed.as_slice()).unwrap().read_to_string(&mut decoded).unwrap();
+        assert_eq!(data, decoded);
+    }
+
+    // chunks of single frames
+    let frames = Compression::Zstd.frames(&zstd_data).unwrap();
+    assert_eq!(1, chunks(&zstd_data, &frames, usize::MAX).len());
+    let single = chunks(&zstd_data, &frames, 1);
+    assert_eq!(frames.len(), single.len());
+    let (r, stats) = process_chunks::<TemperStat>(&single, Compression::Zstd, NonZeroUsize::new(3).unwrap(), 1024, ParseOptions::default()).unwrap();
+    assert_eq!(expected.to_string(), r.to_string());
+    assert_eq!(lines, stats.lines);
+
+    // a frame much bigger than the buffers is decoded by buffers
+    let big = data.repeat(30);
+    let frames = [zstd::bulk::compress(big.as_bytes(), 1).unwrap(), zstd::bulk::compress(data.as_bytes(), 1).unwrap()].concat();
+    let whole = [big.as_bytes(), data.as_bytes()].concat();
+    let (m, big_lines, _) = process_block::<TemperStat>(&whole, 100);
+    let mut big_expected = TemperStatResult::new();
+    big_expected.aggregate(&m);
+    let (r, stats) = process_compressed::<TemperStat>(&frames, Compression::Zstd, NonZeroUsize::new(2).unwrap(), 1024, ParseOptions::default()).unwrap();
+    assert_eq!(big_expected.to_string(), r.to_string());
+    assert_eq!(big_lines, stats.lines);
+}
+
+#[test]
+fn test_bgzf_members() {
+    // BGZF empty block (EOF marker) from the SAM/BAM specification
+    const BGZF_EOF: [u8; 28] = [
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+        0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let data = [BGZF_EOF, BGZF_EOF].concat();
+    assert_eq!(Some(vec![&BGZF_EOF[..], &BGZF_EOF[..]]), bgzf_members(&data));
+    assert_eq!(None, bgzf_members(&data[..40]));
+}
@@ -0,0 +1,200 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Result, Write};
+use std::str::FromStr;
+use crate::histogram::Quantile;
+use crate::result::TemperStatResult;
+use crate::TemperStat;
+
+/// CSV column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Station,
+    Min,
+    Mean,
+    Max,
+    Count,
+    Sum,
+    /// Population variance in squared degrees
+    Variance,
+    /// Population standard deviation
+    StdDev,
+    /// Exact quantile, empty without histogram
+    Quantile(Quantile),
+}
+
+impl Column {
+    pub const DEFAULT: [Column; 6] = [Column::Station, Column::Min, Column::Mean, Column::Max, Column::Count, Column::Sum];
+
+    pub fn name(&self) -> String {
+        match self {
+            Column::Station => "station".to_string(),
+            Column::Min => "min".to_string(),
+            Column::Mean => "mean".to_string(),
+            Column::Max => "max".to_string(),
+            Column::Count => "count".to_string(),
+            Column::Sum => "sum".to_string(),
+            Column::Variance => "variance".to_string(),
+            Column::StdDev => "stddev".to_string(),
+            Column::Quantile(q) => q.to_string(),
+        }
+    }
+
+    /// Parse comma separated column list, e.g. `station,mean`
+    pub fn parse_list(s: &str) -> std::result::Result<Vec<Column>, String> {
+        s.split(',').map(|c| c.trim().parse()).collect()
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "station" => Ok(Column::Station),
+            "min" => Ok(Column::Min),
+            "mean" => Ok(Column::Mean),
+            "max" => Ok(Column::Max),
+            "count" => Ok(Column::Count),
+            "sum" => Ok(Column::Sum),
+            "variance" => Ok(Column::Variance),
+            "stddev" => Ok(Column::StdDev),
+            _ => s.parse().map(Column::Quantile).map_err(|_| format!("unknown column `{s}`")),
+        }
+    }
+}
+
+impl Display for Column {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// CSV writer options
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvOptions {
+    /// `b','` for CSV, `b'\t'` for TSV
+    pub delimiter: u8,
+    pub columns: Vec<Column>,
+    pub header: bool,
+    /// Digits after decimal point of temperature columns
+    pub precision: usize,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions { delimiter: b',', columns: Column::DEFAULT.to_vec(), header: true, precision: 1 }
+    }
+}
+
+impl CsvOptions {
+    pub fn tsv() -> Self {
+        CsvOptions { delimiter: b'\t', ..Self::default() }
+    }
+}
+
+impl TemperStatResult<'_> {
+    /// Write the result as CSV/TSV row by row, station names are quoted by RFC 4180 when needed.
+    /// Not UTF-8 station names are written with U+FFFD replacement characters.
+    pub fn write_csv<W: Write>(&self, mut w: W, options: &CsvOptions) -> Result<()> {
+        if options.header {
+            for (i, c) in options.columns.iter().enumerate() {
+                if i != 0 { w.write_all(&[options.delimiter])?; }
+                w.write_all(c.name().as_bytes())?;
+            }
+            w.write_all(b"\n")?;
+        }
+
+        for (name, stat) in self.iter() {
+            for (i, c) in options.columns.iter().enumerate() {
+                if i != 0 { w.write_all(&[options.delimiter])?; }
+                write_csv_field(&mut w, *c, name, stat, options)?;
+            }
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    pub fn to_csv(&self, options: &CsvOptions) -> String {
+        let mut buf = Vec::new();
+        self.write_csv(&mut buf, options).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+fn write_csv_field<W: Write>(w: &mut W, column: Column, name: &[u8], s: &TemperStat, options: &CsvOptions) -> Result<()> {
+    let p = options.precision;
+    match column {
+        Column::Station => write_csv_string(w, name, options.delimiter),
+        Column::Min => write!(w, "{:.p$}", s.min() as f64 / 10.0),
+        Column::Mean => write!(w, "{:.p$}", s.mean()),
+        Column::Max => write!(w, "{:.p$}", s.max() as f64 / 10.0),
+        Column::Count => write!(w, "{}", s.count()),
+        Column::Sum => write!(w, "{:.p$}", s.sum() as f64 / 10.0),
+        Column::Variance => match s.variance() {
+            Some(v) => write!(w, "{v:.p$}"),
+            None => Ok(()),
+        },
+        Column::StdDev => match s.std_dev() {
+            Some(d) => write!(w, "{d:.p$}"),
+            None => Ok(()),
+        },
+        Column::Quantile(q) => match s.quantile(q.fraction()) {
+            Some(t) => write!(w, "{:.p$}", t as f64 / 10.0),
+            None => Ok(()),
+        },
+    }
+}
+
+/// Write RFC 4180 field, quoted if it contains delimiter, quote or line break
+pub fn write_csv_string<W: Write>(w: &mut W, s: &[u8], delimiter: u8) -> Result<()> {
+    let s = String::from_utf8_lossy(s);
+    if s.bytes().any(|b| b == delimiter || b == b'"' || b == b'\n' || b == b'\r') {
+        w.write_all(b"\"")?;
+        w.write_all(s.replace('"', "\"\"").as_bytes())?;
+        w.write_all(b"\"")
+    } else {
+        w.write_all(s.as_bytes())
+    }
+}
+
+#[test]
+fn test_write_csv() {
+    use crate::{process_block_stats, ParseOptions};
+
+    let block = "Abha;5.0\nAbha;-1.5\nSay \"hi\", world;1.0\n愛媛県;2.0\n".as_bytes();
+    let (m, _) = process_block_stats(block, 10, ParseOptions { variance: true, ..Default::default() }, &mut Default::default());
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+
+    assert_eq!("\
+station,min,mean,max,count,sum
+Abha,-1.5,1.8,5.0,2,3.5
+\"Say \"\"hi\"\", world\",1.0,1.0,1.0,1,1.0
+愛媛県,2.0,2.0,2.0,1,2.0
+", r.to_csv(&CsvOptions::default()));
+
+    let options = CsvOptions {
+        columns: Column::parse_list("count,station,mean").unwrap(),
+        header: false,
+        precision: 3,
+        ..CsvOptions::tsv()
+    };
+    assert_eq!("\
+2\tAbha\t1.750
+1\t\"Say \"\"hi\"\", world\"\t1.000
+1\t愛媛県\t2.000
+", r.to_csv(&options));
+
+    let options = CsvOptions { columns: Column::parse_list("station,variance,stddev").unwrap(), precision: 4, ..CsvOptions::default() };
+    assert!(r.to_csv(&options).starts_with("station,variance,stddev\nAbha,10.5625,3.2500\n"));
+    // without sums of squares the fields are empty
+    let (m, _, _) = crate::process_block(block, 10);
+    let mut plain = TemperStatResult::new();
+    plain.aggregate(&m);
+    assert!(plain.to_csv(&options).starts_with("station,variance,stddev\nAbha,,\n"));
+
+    let options = CsvOptions { columns: Column::parse_list("station,median,p99").unwrap(), ..CsvOptions::default() };
+    assert!(r.to_csv(&options).starts_with("station,median,p99\nAbha,,\n"));
+    assert_eq!(Column::Quantile(Quantile::MEDIAN), "median".parse().unwrap());
+    assert!(Column::parse_list("station,mode").is_err());
+}
@@ -0,0 +1,139 @@
+use std::fmt::{Display, Formatter};
+use std::str::from_utf8;
+use memchr::memchr_iter;
+use crate::i16_from_bytes;
+use crate::stats::ParseError;
+
+/// Rejected line sample
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic<'a> {
+    /// Absolute byte offset of the line begin in the file
+    pub offset: usize,
+    /// Line bytes without `\n`
+    pub line: &'a [u8],
+    pub error: ParseError,
+}
+
+impl Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match from_utf8(self.line) {
+            Ok(s) => write!(f, "offset {}: {}: {s:?}", self.offset, self.error),
+            Err(_) => write!(f, "offset {}: {}: \"{}\"", self.offset, self.error, self.line.escape_ascii()),
+        }
+    }
+}
+
+/// Rejected line samples of a block, at most `limit` samples are kept.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics<'a> {
+    base: usize,
+    limit: usize,
+    samples: Vec<Diagnostic<'a>>,
+    dropped: usize,
+}
+
+impl<'a> Diagnostics<'a> {
+    /// `base` - block offset in the file, `limit` - maximum samples kept, `0` disables collecting
+    pub fn new(base: usize, limit: usize) -> Self {
+        Diagnostics { base, limit, samples: Vec::new(), dropped: 0 }
+    }
+
+    /// Record line starting at `offset` from the block begin
+    #[cold]
+    pub fn push(&mut self, offset: usize, line: &'a [u8], error: ParseError) {
+        if self.samples.len() < self.limit {
+            self.samples.push(Diagnostic { offset: self.base + offset, line, error });
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    pub fn samples(&self) -> &[Diagnostic<'a>] {
+        &self.samples
+    }
+
+    /// Number of rejected lines not kept because of the limit
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty() && self.dropped == 0
+    }
+
+    /// Append samples of other block, keeping at most `limit` samples with the lowest offsets
+    pub fn merge(&mut self, other: Diagnostics<'a>) {
+        self.samples.extend(other.samples);
+        self.samples.sort_unstable_by_key(|d| d.offset);
+        self.dropped += other.dropped;
+        if self.samples.len() > self.limit {
+            self.dropped += self.samples.len() - self.limit;
+            self.samples.truncate(self.limit);
+        }
+    }
+
+    /// Line numbers (1-based) of the samples, `data` is the whole file.
+    /// Lines are counted only here, so the parsing path doesn't pay for it.
+    pub fn line_numbers(&self, data: &[u8]) -> Vec<usize> {
+        let mut numbers = Vec::with_capacity(self.samples.len());
+        let mut pos: usize = 0;
+        let mut line: usize = 1;
+        for d in self.samples.iter() {
+            let end = d.offset.min(data.len());
+            if end >= pos {
+                line += memchr_iter(b'\n', &data[pos..end]).count();
+                pos = end;
+            } else {
+                line = memchr_iter(b'\n', &data[..end]).count() + 1;
+                pos = end;
+            }
+            numbers.push(line);
+        }
+        numbers
+    }
+}
+
+/// Reason of temperature parse error: a valid temperature followed by not digit bytes is
+/// `TrailingGarbage` (e.g. `\r` of CRLF line endings), all other is `BadTemperature`.
+pub fn temperature_error(temp: &[u8]) -> ParseError {
+    for l in (3..=5).rev() {
+        if temp.len() > l && !temp[l].is_ascii_digit() && i16_from_bytes(&temp[..l]).is_some() {
+            return ParseError::TrailingGarbage;
+        }
+    }
+    ParseError::BadTemperature
+}
+
+#[test]
+fn test_temperature_error() {
+    assert_eq!(ParseError::TrailingGarbage, temperature_error(b"12.3\r"));
+    assert_eq!(ParseError::TrailingGarbage, temperature_error(b"-1.0 C"));
+    assert_eq!(ParseError::TrailingGarbage, temperature_error(b"-12.0;1.0"));
+    assert_eq!(ParseError::BadTemperature, temperature_error(b"12.34"));
+    assert_eq!(ParseError::BadTemperature, temperature_error(b"1e1"));
+    assert_eq!(ParseError::BadTemperature, temperature_error(b""));
+}
+
+#[test]
+fn test_diagnostics() {
+    let data = b"a;1.0\nb\nc;2.0\nd;x\ne;3.0\r\n";
+    let mut d = Diagnostics::new(0, 2);
+    d.push(6, &data[6..7], ParseError::MissingDelimiter);
+
+    let mut other = Diagnostics::new(14, 1);
+    other.push(0, &data[14..17], ParseError::BadTemperature);
+    other.push(4, &data[18..23], ParseError::TrailingGarbage);
+    d.merge(other);
+
+    assert_eq!(2, d.samples().len());
+    assert_eq!(1, d.dropped());
+    assert_eq!(14, d.samples()[1].offset);
+    assert_eq!(vec![2, 4], d.line_numbers(data));
+    assert_eq!("offset 14: bad temperature: \"d;x\"", d.samples()[1].to_string());
+
+    // the samples with the lowest offsets are kept
+    let mut first = Diagnostics::new(0, 1);
+    first.merge(d);
+    assert_eq!(6, first.samples()[0].offset);
+    assert_eq!(2, first.dropped());
+}
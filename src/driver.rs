@@ -0,0 +1,313 @@
+//! Parallel aggregation of measurement files, as done by the `one-brc` binary.
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//! use one_brc::driver::{Driver, Inputs};
+//!
+//! let mut inputs = Inputs::open(&[PathBuf::from("measurements.txt")])?;
+//! let run = Driver::new().strict(true).process::<one_brc::TemperStat>(&mut inputs)?;
+//! println!("{}", run.result);
+//! eprintln!("{}", run.stats);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Uncompressed memory mapped files and slices share one worker pool by `process_files`,
+//! streams and compressed data are processed one by one with all threads.
+use std::io::{Error, Result};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::thread::available_parallelism;
+use crate::aggregator::Aggregator;
+use crate::compress::{auto_decoder, process_compressed, Compression};
+use crate::input::Input;
+use crate::parallel::{process_files, process_files_combined, FileResult, CHUNK_SIZE};
+use crate::result::TemperStatResult;
+use crate::stats::{RunStats, TooManyStations};
+use crate::stream::{process_stream, STREAM_BUFFER_SIZE};
+use crate::{ParseOptions, TemperStat};
+
+/// Opened input files
+pub struct Inputs {
+    files: Vec<(PathBuf, Input)>,
+}
+
+impl Inputs {
+    /// Open files by `Input::open`, the error message starts with the path of the failed file
+    pub fn open(paths: &[PathBuf]) -> Result<Inputs> {
+        let files = paths.iter()
+            .map(|path| Input::open(path).map(|input| (path.clone(), input)).map_err(|e| with_path(path, e)))
+            .collect::<Result<_>>()?;
+        Ok(Inputs { files })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Inputs which are read as streams or decompressed, so rejected lines are not sampled
+    pub fn not_mapped(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().filter(|(_, input)| data(input).is_none()).map(|(path, _)| path.as_path())
+    }
+}
+
+/// Uncompressed bytes of a mapped input
+fn data(input: &Input) -> Option<&[u8]> {
+    match input {
+        Input::Mapped(mmap) if Compression::detect(mmap) == Compression::None => Some(&mmap[..]),
+        Input::Empty => Some(&[]),
+        _ => None,
+    }
+}
+
+fn with_path(path: &Path, e: Error) -> Error {
+    Error::new(e.kind(), format!("{}: {e}", path.display()))
+}
+
+/// Result of a run: combined result and line counters, and the same per input
+#[derive(Debug, Default)]
+pub struct Aggregation<'a, A = TemperStat> {
+    pub result: TemperStatResult<'a, A>,
+    pub stats: RunStats,
+    /// Line counters and diagnostics of every input, station results only with `Driver::per_file`
+    pub files: Vec<FileResult<'a, A>>,
+    /// Uncompressed bytes of every input, empty for streams and compressed inputs,
+    /// e.g. for `Diagnostics::line_numbers`
+    pub data: Vec<&'a [u8]>,
+}
+
+impl<'a, A: Aggregator> Aggregation<'a, A> {
+    /// Combine `result` of the mapped inputs with the file results, which are dropped if not `per_file`
+    fn new(mut result: TemperStatResult<'a, A>, mut files: Vec<FileResult<'a, A>>, data: Vec<&'a [u8]>, per_file: bool) -> Self {
+        let mut stats = RunStats::new();
+        for file in files.iter_mut() {
+            result.merge(&file.result);
+            stats.merge(&file.stats);
+            if !per_file {
+                file.result = TemperStatResult::new();
+            }
+        }
+        Aggregation { result, stats, files, data }
+    }
+}
+
+/// Parallel aggregation options, set by chained calls:
+/// `Driver::new().threads(n).chunk_size(bytes).strict(true)`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Driver {
+    threads: NonZeroUsize,
+    chunk_size: usize,
+    buffer_size: usize,
+    options: ParseOptions,
+    samples: usize,
+    per_file: bool,
+}
+
+impl Default for Driver {
+    fn default() -> Self {
+        Driver {
+            threads: available_parallelism().unwrap_or(NonZeroUsize::MIN),
+            chunk_size: CHUNK_SIZE,
+            buffer_size: STREAM_BUFFER_SIZE,
+            options: ParseOptions::default(),
+            samples: 0,
+            per_file: false,
+        }
+    }
+}
+
+impl Driver {
+    /// All available threads, `CHUNK_SIZE` chunks, `STREAM_BUFFER_SIZE` buffers, not strict,
+    /// the combined result only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Bytes of a mapped file taken by a worker at once
+    pub fn chunk_size(mut self, chunk_size: NonZeroUsize) -> Self {
+        self.chunk_size = chunk_size.get();
+        self
+    }
+
+    /// Read buffer size of streams
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Check station names by `validate_name`, and fail with `TooManyStations` if the combined
+    /// result has more than `STATIONS_MAX` stations
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+
+    pub fn parse_options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Rejected line samples kept per mapped input, see `Diagnostics`
+    pub fn diagnostics(mut self, samples: usize) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Keep the station results of every input in `Aggregation::files`. Otherwise the workers
+    /// collect only the combined result, which takes much less memory for many inputs.
+    pub fn per_file(mut self, per_file: bool) -> Self {
+        self.per_file = per_file;
+        self
+    }
+
+    /// Process data in memory, compressed data is detected and decompressed
+    pub fn process_slice<'a, A: Aggregator + Send>(&self, data: &'a [u8]) -> Result<Aggregation<'a, A>> {
+        let run = match Compression::detect(data) {
+            Compression::None => {
+                let (total, files) = self.process_mapped(&[data]);
+                Aggregation::new(total, files, vec![data], self.per_file)
+            }
+            compression => {
+                let (result, stats) = process_compressed(data, compression, self.threads, self.buffer_size, self.options)?;
+                Aggregation::new(TemperStatResult::new(), vec![FileResult { result, stats, ..FileResult::default() }], vec![&[]], self.per_file)
+            }
+        };
+        self.check(run)
+    }
+
+    /// Process all inputs, streams are read to the end.
+    /// The error message starts with the path of the failed input.
+    pub fn process<'a, A: Aggregator + Send>(&self, inputs: &'a mut Inputs) -> Result<Aggregation<'a, A>> {
+        let mut files: Vec<Option<FileResult<'a, A>>> = Vec::with_capacity(inputs.len());
+        for (path, input) in inputs.files.iter_mut() {
+            files.push(match input {
+                Input::Stream(reader) => {
+                    let (result, stats) = auto_decoder(reader)
+                        .and_then(|r| process_stream(r, self.threads, self.buffer_size, self.options))
+                        .map_err(|e| with_path(path, e))?;
+                    Some(FileResult { result, stats, ..FileResult::default() })
+                }
+                _ => None,
+            });
+        }
+
+        let inputs: &'a Inputs = inputs;
+        let inputs = &inputs.files;
+        let mapped: Vec<&'a [u8]> = inputs.iter().filter_map(|(_, input)| data(input)).collect();
+        let (total, mapped_results) = self.process_mapped(&mapped);
+        let mut mapped_results = mapped_results.into_iter();
+
+        let mut data_list = Vec::with_capacity(inputs.len());
+        for ((path, input), file) in inputs.iter().zip(files.iter_mut()) {
+            data_list.push(data(input).unwrap_or_default());
+            if file.is_some() {
+                continue;
+            }
+            *file = Some(match (input, data(input)) {
+                (_, Some(_)) => mapped_results.next().unwrap(),
+                (Input::Mapped(mmap), None) => {
+                    let (result, stats) = process_compressed(mmap, Compression::detect(mmap), self.threads, self.buffer_size, self.options)
+                        .map_err(|e| with_path(path, e))?;
+                    FileResult { result, stats, ..FileResult::default() }
+                }
+                _ => FileResult::default(),
+            });
+        }
+
+        self.check(Aggregation::new(total, files.into_iter().map(Option::unwrap).collect(), data_list, self.per_file))
+    }
+
+    /// Apply the strict mode limit of stations to the combined result
+    fn check<'a, A: Aggregator>(&self, run: Aggregation<'a, A>) -> Result<Aggregation<'a, A>> {
+        if self.options.strict {
+            TooManyStations::check(run.result.len())?;
+        }
+        Ok(run)
+    }
+
+    /// Combined result, empty if `per_file`, and the file results
+    fn process_mapped<'a, A: Aggregator + Send>(&self, data: &[&'a [u8]]) -> (TemperStatResult<'a, A>, Vec<FileResult<'a, A>>) {
+        match self.per_file {
+            true => (TemperStatResult::new(), process_files(data, self.threads, self.chunk_size, self.options, self.samples)),
+            false => process_files_combined(data, self.threads, self.chunk_size, self.options, self.samples),
+        }
+    }
+}
+
+#[test]
+fn test_driver() {
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use crate::process_block;
+    use crate::test::TEST_STR_100;
+
+    let data = [TEST_STR_100, "bad line\n"].concat().repeat(30);
+    let (map, lines, errors) = process_block::<TemperStat>(data.as_bytes(), 100);
+    let mut expected = TemperStatResult::new();
+    expected.aggregate(&map);
+
+    let driver = Driver::new().threads(NonZeroUsize::new(3).unwrap()).chunk_size(NonZeroUsize::new(100).unwrap()).diagnostics(2);
+    let run = driver.process_slice::<TemperStat>(data.as_bytes()).unwrap();
+    assert_eq!(expected.to_string(), run.result.to_string());
+    assert_eq!((lines, errors), (run.stats.lines, run.stats.rejected()));
+    assert_eq!(2, run.files[0].diagnostics.samples().len());
+    assert_eq!(vec![101, 202], run.files[0].diagnostics.line_numbers(run.data[0]));
+
+    let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    gz.write_all(data.as_bytes()).unwrap();
+    let gz = gz.finish().unwrap();
+    let run = driver.process_slice::<TemperStat>(&gz).unwrap();
+    assert_eq!(expected.to_string(), run.result.to_string());
+    assert_eq!(lines, run.stats.lines);
+
+    // plain, compressed and empty files combined
+    let dir = std::env::temp_dir().join(format!("one-brc-driver-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths: Vec<PathBuf> = ["a.txt", "b.txt.gz", "empty.txt"].iter().map(|f| dir.join(f)).collect();
+    std::fs::write(&paths[0], &data).unwrap();
+    std::fs::write(&paths[1], &gz).unwrap();
+    std::fs::write(&paths[2], b"").unwrap();
+
+    let mut inputs = Inputs::open(&paths).unwrap();
+    assert_eq!(vec![paths[1].as_path()], inputs.not_mapped().collect::<Vec<_>>());
+    let run = driver.clone().strict(true).per_file(true).process::<TemperStat>(&mut inputs).unwrap();
+    assert_eq!(3, run.files.len());
+    assert_eq!(2 * lines, run.stats.lines);
+    assert_eq!(0, run.files[2].stats.lines);
+    assert_eq!(expected.to_string(), run.files[0].result.to_string());
+    assert_eq!(expected.to_string(), run.files[1].result.to_string());
+    for ((_, a), (_, b)) in expected.iter().zip(run.result.iter()) {
+        assert_eq!(2 * a.count(), b.count());
+    }
+
+    // the same combined result without file results
+    let mut inputs = Inputs::open(&paths).unwrap();
+    let combined = driver.clone().process::<TemperStat>(&mut inputs).unwrap();
+    assert_eq!(run.result.to_string(), combined.result.to_string());
+    assert!(combined.files.iter().all(|f| f.result.is_empty()));
+    assert_eq!(lines, combined.files[1].stats.lines);
+
+    // more stations than the rules allow are an error of strict mode only
+    let many: String = (0..crate::STATIONS_MAX + 1).map(|i| format!("s{i};1.0\n")).collect();
+    assert_eq!(crate::STATIONS_MAX + 1, driver.process_slice::<TemperStat>(many.as_bytes()).unwrap().result.len());
+    let e = driver.strict(true).process_slice::<TemperStat>(many.as_bytes()).err().unwrap();
+    assert!(TooManyStations::is(&e));
+    assert_eq!("10001 unique stations, maximum is 10000", e.to_string());
+
+    let missing = dir.join("missing.txt");
+    let e = Inputs::open(&[paths[0].clone(), missing.clone()]).err().unwrap();
+    assert!(e.to_string().starts_with(&missing.display().to_string()));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
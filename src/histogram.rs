@@ -0,0 +1,292 @@
+//! Exact temperature histograms for quantiles.
+//!
+//! Temperatures are integer tenths in `-999..=999`, so a bucket per value gives exact quantiles.
+//! Only the range of values seen so far is allocated, with `u32` counters widened to `u64`
+//! only when a count doesn't fit: a station of the 1BRC data set takes a few hundred bytes,
+//! at most 8 KB, in every worker table.
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+const MIN_VALUE: i16 = -999;
+const MAX_VALUE: i16 = 999;
+/// Buckets added at once around the new value, so the range grows rarely
+const GROW: i16 = 32;
+
+/// Counts of every temperature value of a station
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    /// Value of the first bucket
+    base: i16,
+    counts: Counts,
+}
+
+/// Bucket counts, `u32` until a count doesn't fit
+#[derive(Clone, Debug)]
+enum Counts {
+    Narrow(Vec<u32>),
+    Wide(Vec<u64>),
+}
+
+impl Default for Counts {
+    fn default() -> Self {
+        Counts::Narrow(Vec::new())
+    }
+}
+
+impl Counts {
+    fn len(&self) -> usize {
+        match self {
+            Counts::Narrow(c) => c.len(),
+            Counts::Wide(c) => c.len(),
+        }
+    }
+
+    fn get(&self, i: usize) -> u64 {
+        match self {
+            Counts::Narrow(c) => c[i] as u64,
+            Counts::Wide(c) => c[i],
+        }
+    }
+
+    #[inline]
+    fn add(&mut self, i: usize, n: u64) {
+        match self {
+            Counts::Narrow(c) => match u32::try_from(n).ok().and_then(|n| c[i].checked_add(n)) {
+                Some(sum) => c[i] = sum,
+                None => self.widen(i, n),
+            },
+            Counts::Wide(c) => c[i] += n,
+        }
+    }
+
+    #[cold]
+    fn widen(&mut self, i: usize, n: u64) {
+        if let Counts::Narrow(c) = self {
+            *self = Counts::Wide(c.iter().map(|&n| n as u64).collect());
+        }
+        self.add(i, n);
+    }
+
+    /// Counts moved to `offset` of `len` zero buckets
+    fn regrow(&self, offset: usize, len: usize) -> Counts {
+        fn regrow<T: Copy + Default>(c: &[T], offset: usize, len: usize) -> Vec<T> {
+            let mut counts = vec![T::default(); len];
+            counts[offset..offset + c.len()].copy_from_slice(c);
+            counts
+        }
+        match self {
+            Counts::Narrow(c) => Counts::Narrow(regrow(c, offset, len)),
+            Counts::Wide(c) => Counts::Wide(regrow(c, offset, len)),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restore histogram from raw buckets, e.g. loaded from a snapshot
+    pub fn from_raw(base: i16, counts: Vec<u64>) -> Option<Self> {
+        let end = base as isize + counts.len() as isize;
+        if !counts.is_empty() && (base < MIN_VALUE || end > MAX_VALUE as isize + 1) {
+            return None;
+        }
+        let counts = match counts.iter().map(|&n| u32::try_from(n)).collect() {
+            Ok(narrow) => Counts::Narrow(narrow),
+            Err(_) => Counts::Wide(counts),
+        };
+        Some(Histogram { base, counts })
+    }
+
+    /// Value of the first bucket
+    pub fn base(&self) -> i16 {
+        self.base
+    }
+
+    /// Counts of all buckets from `base`
+    pub fn counts(&self) -> impl ExactSizeIterator<Item = u64> + '_ {
+        (0..self.counts.len()).map(|i| self.counts.get(i))
+    }
+
+    /// Count value `v`, values outside `-999..=999` are counted at the nearest bound
+    #[inline]
+    pub fn add(&mut self, v: i16) {
+        self.add_count(v, 1);
+    }
+
+    #[inline]
+    fn add_count(&mut self, v: i16, n: u64) {
+        let v = v.clamp(MIN_VALUE, MAX_VALUE);
+        let i = v as isize - self.base as isize;
+        if i < 0 || i >= self.counts.len() as isize {
+            self.extend(v);
+        }
+        self.counts.add((v - self.base) as usize, n);
+    }
+
+    /// Extend the bucket range to include `v` in `-999..=999`
+    #[cold]
+    fn extend(&mut self, v: i16) {
+        if self.counts.len() == 0 {
+            self.base = (v - GROW).max(MIN_VALUE);
+            self.counts = Counts::Narrow(vec![0; ((v + GROW).min(MAX_VALUE) - self.base) as usize + 1]);
+            return;
+        }
+        let end = self.base + self.counts.len() as i16 - 1;
+        let base = if v < self.base { (v - GROW).max(MIN_VALUE) } else { self.base };
+        let end = if v > end { (v + GROW).min(MAX_VALUE) } else { end };
+        self.counts = self.counts.regrow((self.base - base) as usize, (end - base) as usize + 1);
+        self.base = base;
+    }
+
+    /// Add counts of other histogram bucket by bucket
+    pub fn merge(&mut self, other: &Histogram) {
+        for (v, n) in other.iter() {
+            self.add_count(v, n);
+        }
+    }
+
+    /// Not empty buckets as `(value, count)` in value order
+    pub fn iter(&self) -> impl Iterator<Item = (i16, u64)> + '_ {
+        self.counts().enumerate().filter(|&(_, n)| n != 0).map(|(i, n)| (self.base + i as i16, n))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts().sum()
+    }
+
+    /// Exact quantile `q` in `0.0..=1.0` by the nearest-rank method: the smallest value with
+    /// at least `q * count` values less or equal to it. `None` for an empty histogram.
+    pub fn quantile(&self, q: f64) -> Option<i16> {
+        let count = self.count();
+        let rank = ((q * count as f64).ceil() as u64).clamp(1, count.max(1));
+        let mut seen = 0;
+        for (v, n) in self.iter() {
+            seen += n;
+            if seen >= rank {
+                return Some(v);
+            }
+        }
+        None
+    }
+}
+
+impl PartialEq for Histogram {
+    /// The same buckets, however wide the counters are
+    fn eq(&self, other: &Self) -> bool {
+        self.base == other.base && self.counts().eq(other.counts())
+    }
+}
+
+impl Eq for Histogram {}
+
+/// Quantile requested for the output, stored in parts per million so it can be compared exactly
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Quantile(u32);
+
+impl Quantile {
+    pub const MEDIAN: Quantile = Quantile(500_000);
+    /// Median, p5, p95 and p99 of `--percentiles`
+    pub const PERCENTILES: [Quantile; 4] = [Quantile::MEDIAN, Quantile(50_000), Quantile(950_000), Quantile(990_000)];
+
+    pub fn fraction(&self) -> f64 {
+        self.0 as f64 / 1e6
+    }
+}
+
+impl FromStr for Quantile {
+    type Err = String;
+
+    /// `median`, percentile `p95`, `p99.9` or fraction `0.95`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fraction = match s {
+            "median" => Some(0.5),
+            _ => match s.strip_prefix('p') {
+                Some(p) => p.parse::<f64>().ok().map(|p| p / 100.0),
+                None => s.parse::<f64>().ok(),
+            },
+        };
+        match fraction {
+            Some(f) if (0.0..=1.0).contains(&f) => Ok(Quantile((f * 1e6).round() as u32)),
+            _ => Err(format!("invalid quantile `{s}`")),
+        }
+    }
+}
+
+impl Display for Quantile {
+    /// Column name: `median` or percentile, e.g. `p95`, `p99.9`
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if *self == Quantile::MEDIAN {
+            return write!(f, "median");
+        }
+        let p = format!("{:.4}", self.0 as f64 / 1e4);
+        write!(f, "p{}", p.trim_end_matches('0').trim_end_matches('.'))
+    }
+}
+
+#[test]
+fn test_histogram() {
+    let mut h = Histogram::new();
+    assert_eq!(None, h.quantile(0.5));
+
+    let values: Vec<i16> = (-999..=999).chain([0, 0, 0, 999, -999]).collect();
+    for &v in values.iter().rev() {
+        h.add(v);
+    }
+    assert_eq!(values.len() as u64, h.count());
+    assert_eq!((-999, 1999), (h.base(), h.counts().len()));
+
+    let mut sorted = values.clone();
+    sorted.sort();
+    for q in [0.0, 0.01, 0.05, 0.25, 0.5, 0.75, 0.95, 0.99, 0.999, 1.0] {
+        let rank = ((q * sorted.len() as f64).ceil() as usize).max(1);
+        assert_eq!(Some(sorted[rank - 1]), h.quantile(q), "{q}");
+    }
+
+    // merge is the same as adding all values to one histogram
+    let mut a = Histogram::new();
+    let mut b = Histogram::new();
+    for (i, &v) in values.iter().enumerate() {
+        if i % 3 == 0 { a.add(v) } else { b.add(v) }
+    }
+    a.merge(&b);
+    assert_eq!(h.iter().collect::<Vec<_>>(), a.iter().collect::<Vec<_>>());
+
+    // a narrow range takes a few buckets
+    let mut small = Histogram::new();
+    [150, 151, 149, 160].iter().for_each(|&v| small.add(v));
+    assert!(small.counts().len() <= 2 * GROW as usize + 12);
+    assert_eq!(Some(150), small.quantile(0.5));
+
+    assert!(Histogram::from_raw(990, vec![1; 20]).is_none());
+    assert_eq!(Some(small.clone()), Histogram::from_raw(small.base(), small.counts().collect()));
+
+    // values beyond the bounds are counted at the bounds
+    let mut edges = Histogram::new();
+    for v in [-999, -1000, i16::MIN, 999, 1000, i16::MAX] {
+        edges.add(v);
+    }
+    assert_eq!(vec![(-999, 3), (999, 3)], edges.iter().collect::<Vec<_>>());
+    assert_eq!((-999, 1999), (edges.base(), edges.counts().len()));
+
+    // counters are widened when a count doesn't fit in `u32`
+    let mut wide = small.clone();
+    wide.add_count(150, u32::MAX as u64);
+    wide.add(160);
+    wide.merge(&wide.clone());
+    assert_eq!(Some(2 * (u32::MAX as u64 + 1)), wide.iter().find(|&(v, _)| v == 150).map(|(_, n)| n));
+    assert_eq!(2 * (u32::MAX as u64 + 5), wide.count());
+    assert_eq!(Some(wide.clone()), Histogram::from_raw(wide.base(), wide.counts().collect()));
+}
+
+#[test]
+fn test_quantile() {
+    for (s, name) in [("median", "median"), ("0.5", "median"), ("p95", "p95"), ("0.05", "p5"), ("p99.9", "p99.9"), ("0.99999", "p99.999"), ("1", "p100")] {
+        assert_eq!(name, s.parse::<Quantile>().unwrap().to_string(), "{s}");
+    }
+    assert_eq!(0.95, "p95".parse::<Quantile>().unwrap().fraction());
+    for s in ["p101", "-0.1", "1.5", "median2", "p"] {
+        assert!(s.parse::<Quantile>().is_err(), "{s}");
+    }
+}
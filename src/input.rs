@@ -0,0 +1,73 @@
+use std::fs::File;
+use std::io::{stdin, Read, Result};
+use std::path::{Path, PathBuf};
+use memmap::Mmap;
+
+/// Opened input file
+pub enum Input {
+    /// Empty file, can't be mapped
+    Empty,
+    Mapped(Mmap),
+    /// Stdin, pipe or other not regular file
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Input {
+    /// Memory map regular file, other inputs and `-` for stdin are read as stream
+    pub fn open(path: &Path) -> Result<Input> {
+        if path.as_os_str() == "-" {
+            return Ok(Input::Stream(Box::new(stdin())));
+        }
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            Ok(Input::Stream(Box::new(file)))
+        } else if metadata.len() == 0 {
+            Ok(Input::Empty)
+        } else {
+            Ok(Input::Mapped(unsafe { Mmap::map(&file)? }))
+        }
+    }
+}
+
+/// Expand paths containing `*`, `?` or `[` as glob patterns, other paths are kept as is.
+/// An existing file is taken as is even if its name looks like a pattern, e.g. `data[1].txt`.
+/// A pattern without matches is an error.
+pub fn expand_globs(paths: &[PathBuf]) -> std::result::Result<Vec<PathBuf>, String> {
+    let mut result = Vec::new();
+    for path in paths {
+        let pattern = path.to_string_lossy();
+        if !pattern.contains(['*', '?', '[']) || path.exists() {
+            result.push(path.clone());
+            continue;
+        }
+        let matches = glob::glob(&pattern).map_err(|e| format!("{pattern}: {e}"))?;
+        let before = result.len();
+        for m in matches {
+            result.push(m.map_err(|e| e.to_string())?);
+        }
+        if result.len() == before {
+            return Err(format!("{pattern}: no files match the pattern"));
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_expand_globs() {
+    let paths = expand_globs(&[PathBuf::from("src/*.rs"), PathBuf::from("-"), PathBuf::from("missing.txt")]).unwrap();
+    assert!(paths.contains(&PathBuf::from("src/lib.rs")));
+    assert!(paths.contains(&PathBuf::from("src/input.rs")));
+    assert!(!paths.iter().any(|p| p.starts_with("src/bin")));
+    assert_eq!(&PathBuf::from("missing.txt"), paths.last().unwrap());
+    assert!(expand_globs(&[PathBuf::from("src/*.missing")]).is_err());
+
+    // a literal file name with pattern characters
+    let dir = std::env::temp_dir().join(format!("one-brc-glob-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let literal = dir.join("data[1].txt");
+    std::fs::write(&literal, b"").unwrap();
+    assert_eq!(vec![literal.clone()], expand_globs(std::slice::from_ref(&literal)).unwrap());
+    assert!(expand_globs(&[dir.join("data[2].txt")]).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,152 @@
+use std::io::{Result, Write};
+use crate::result::{ExtraStats, TemperStatResult};
+use crate::{TemperStat, Tenths};
+
+/// JSON document layout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JsonLayout {
+    /// `[{"station":"Abha","min":5.0,...}, ...]`
+    #[default]
+    Array,
+    /// `{"Abha":{"min":5.0,...}, ...}`
+    Object,
+}
+
+impl TemperStatResult<'_> {
+    /// Write the result as JSON station by station, the document is never built in memory.
+    /// Not UTF-8 station names are written with U+FFFD replacement characters.
+    pub fn write_json<W: Write>(&self, mut w: W, layout: JsonLayout, extra: &ExtraStats) -> Result<()> {
+        let (open, close) = match layout {
+            JsonLayout::Array => (b'[', b']'),
+            JsonLayout::Object => (b'{', b'}'),
+        };
+
+        w.write_all(&[open])?;
+        for (c, (name, stat)) in self.iter().enumerate() {
+            if c != 0 { w.write_all(b",")?; }
+            match layout {
+                JsonLayout::Array => {
+                    w.write_all(b"{\"station\":")?;
+                    write_json_string(&mut w, name)?;
+                    w.write_all(b",")?;
+                    write_json_fields(&mut w, stat, extra)?;
+                    w.write_all(b"}")?;
+                }
+                JsonLayout::Object => {
+                    write_json_string(&mut w, name)?;
+                    w.write_all(b":{")?;
+                    write_json_fields(&mut w, stat, extra)?;
+                    w.write_all(b"}")?;
+                }
+            }
+        }
+        w.write_all(&[close])
+    }
+
+    pub fn to_json(&self, layout: JsonLayout) -> String {
+        let mut buf = Vec::new();
+        self.write_json(&mut buf, layout, &ExtraStats::default()).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+fn write_json_fields<W: Write>(w: &mut W, s: &TemperStat, extra: &ExtraStats) -> Result<()> {
+    write!(w, "\"min\":{},\"mean\":{:.1},\"max\":{},\"count\":{},\"sum\":{}",
+           Tenths(s.min() as i64), s.mean(), Tenths(s.max() as i64), s.count(), Tenths(s.sum()))?;
+    if extra.variance {
+        match (s.variance(), s.std_dev()) {
+            (Some(v), Some(d)) => write!(w, ",\"variance\":{v},\"stddev\":{d}")?,
+            _ => write!(w, ",\"variance\":null,\"stddev\":null")?,
+        }
+    }
+    for q in extra.quantiles.iter() {
+        match s.quantile(q.fraction()) {
+            Some(t) => write!(w, ",\"{q}\":{}", Tenths(t as i64))?,
+            None => write!(w, ",\"{q}\":null")?,
+        }
+    }
+    Ok(())
+}
+
+/// Write quoted and escaped JSON string
+///
+/// Bytes that aren't valid UTF-8 are written as lone surrogate escapes `\udc80`..`\udcff`
+/// (like Python's `surrogateescape`), valid text never produces those, so distinct names stay distinct.
+pub fn write_json_string<W: Write>(w: &mut W, s: &[u8]) -> Result<()> {
+    w.write_all(b"\"")?;
+    for chunk in s.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            match ch {
+                '"' => w.write_all(b"\\\"")?,
+                '\\' => w.write_all(b"\\\\")?,
+                '\n' => w.write_all(b"\\n")?,
+                '\r' => w.write_all(b"\\r")?,
+                '\t' => w.write_all(b"\\t")?,
+                c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+                c => w.write_all(c.encode_utf8(&mut [0; 4]).as_bytes())?,
+            }
+        }
+        for b in chunk.invalid() {
+            write!(w, "\\udc{b:02x}")?;
+        }
+    }
+    w.write_all(b"\"")
+}
+
+#[test]
+fn test_write_json() {
+    use crate::process_block;
+
+    let (m, _, _) = process_block("Abha;5.0\nAbha;-1.5\nQuo\"te\\;1.0\n愛媛県;2.0\nTab\t\u{1};0.0\n".as_bytes(), 10);
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+
+    assert_eq!("[\
+{\"station\":\"Abha\",\"min\":-1.5,\"mean\":1.8,\"max\":5.0,\"count\":2,\"sum\":3.5},\
+{\"station\":\"Quo\\\"te\\\\\",\"min\":1.0,\"mean\":1.0,\"max\":1.0,\"count\":1,\"sum\":1.0},\
+{\"station\":\"Tab\\t\\u0001\",\"min\":0.0,\"mean\":0.0,\"max\":0.0,\"count\":1,\"sum\":0.0},\
+{\"station\":\"愛媛県\",\"min\":2.0,\"mean\":2.0,\"max\":2.0,\"count\":1,\"sum\":2.0}]", r.to_json(JsonLayout::Array));
+
+    let json = r.to_json(JsonLayout::Object);
+    assert!(json.starts_with("{\"Abha\":{\"min\":-1.5,\"mean\":1.8,"));
+    assert!(json.ends_with("\"愛媛県\":{\"min\":2.0,\"mean\":2.0,\"max\":2.0,\"count\":1,\"sum\":2.0}}"));
+
+    assert_eq!("[]", TemperStatResult::new().to_json(JsonLayout::Array));
+
+    let (m, _) = crate::process_block_stats(b"a;1.0\na;3.0\n", 10, crate::ParseOptions { variance: true, ..Default::default() }, &mut Default::default());
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+    let mut buf = Vec::new();
+    r.write_json(&mut buf, JsonLayout::Object, &ExtraStats { variance: true, ..Default::default() }).unwrap();
+    assert_eq!("{\"a\":{\"min\":1.0,\"mean\":2.0,\"max\":3.0,\"count\":2,\"sum\":4.0,\"variance\":1,\"stddev\":1}}", String::from_utf8(buf).unwrap());
+
+    // without sums of squares the variance is null
+    let (m, _, _) = process_block(b"a;1.0\na;3.0\n", 10);
+    let mut plain = TemperStatResult::new();
+    plain.aggregate(&m);
+    let mut buf = Vec::new();
+    plain.write_json(&mut buf, JsonLayout::Object, &ExtraStats { variance: true, ..Default::default() }).unwrap();
+    assert_eq!("{\"a\":{\"min\":1.0,\"mean\":2.0,\"max\":3.0,\"count\":2,\"sum\":4.0,\"variance\":null,\"stddev\":null}}", String::from_utf8(buf).unwrap());
+
+    let (m, _) = crate::process_block_stats(b"a;1.0\na;3.0\n", 10, crate::ParseOptions { histogram: true, ..Default::default() }, &mut Default::default());
+    let mut h = TemperStatResult::new();
+    h.aggregate(&m);
+    let extra = ExtraStats { quantiles: vec!["median".parse().unwrap(), "p95".parse().unwrap()], ..Default::default() };
+    for (r, quantiles) in [(&h, "\"median\":1.0,\"p95\":3.0"), (&r, "\"median\":null,\"p95\":null")] {
+        let mut buf = Vec::new();
+        r.write_json(&mut buf, JsonLayout::Object, &extra).unwrap();
+        assert_eq!(format!("{{\"a\":{{\"min\":1.0,\"mean\":2.0,\"max\":3.0,\"count\":2,\"sum\":4.0,{quantiles}}}}}"), String::from_utf8(buf).unwrap());
+    }
+
+    // invalid UTF-8 is escaped reversibly, names differing only in invalid bytes stay distinct keys
+    let (m, _, _) = process_block(b"a\xff;1.0\na\xfe;2.0\na\xef\xbf\xbd;3.0\n", 10);
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+    let json = r.to_json(JsonLayout::Object);
+    assert!(json.contains("\"a\\udcfe\":"));
+    assert!(json.contains("\"a\\udcff\":"));
+    assert!(json.contains("\"a\u{fffd}\":"));
+    let mut buf = Vec::new();
+    write_json_string(&mut buf, b"\xe6\x84x\"").unwrap();
+    assert_eq!("\"\\udce6\\udc84x\\\"\"", String::from_utf8(buf).unwrap());
+}
@@ -1,12 +1,37 @@
+pub mod aggregator;
+pub mod backend;
+pub mod cli;
+pub mod compress;
+pub mod csv;
+pub mod diagnostics;
+pub mod driver;
+pub mod histogram;
+pub mod input;
+pub mod json;
+pub mod parallel;
 pub mod result;
+pub mod scan;
+pub mod snapshot;
+pub mod stats;
+pub mod stream;
+pub mod swar;
+pub mod table;
 
 use std::fmt::{Debug, Display, Formatter};
-use ahash::AHashMap;
-use memchr::{memchr_iter, memrchr};
+use memchr::memchr;
+use crate::aggregator::Aggregator;
+use crate::backend::{Backend, Masks};
+use crate::diagnostics::{Diagnostics, temperature_error};
+use crate::histogram::Histogram;
+use crate::stats::{ParseError, RunStats};
+use crate::swar::i16_from_bytes_swar;
+use crate::scan::Records;
+use crate::table::{hash_name, StationTable};
 
 pub const STATION_NAME_MAX_LEN: usize = 100;
 pub const MEASSUREMENT_MAX_LEN: usize = 5;
 pub const LINE_MAX_LEN: usize = STATION_NAME_MAX_LEN + MEASSUREMENT_MAX_LEN + 2; // STATION_NAME_MAX_LEN;MEASSUREMENT_MAX_LEN\n
+pub const STATIONS_MAX: usize = 10_000;
 
 #[cfg(debug_assertions)]
 pub const FILE_PATH: &str = "measurements-small.txt"; // Debug configuration
@@ -89,25 +114,68 @@ fn test_i16_from_bytes() {
     assert_eq!(None, crate::i16_from_bytes(" 1.0".as_bytes()));
 }
 
-/// **Temper**ature **Stat**istics
-#[derive(Clone, Debug)]
+/// **Temper**ature **Stat**istics, the default `Aggregator`
+#[derive(Clone, Debug, Default)]
 pub struct TemperStat {
     min: i16,
     max: i16,
     sum: i64,
-    count: usize
+    /// Sum of squares for the variance, kept only in variance mode and dropped if it overflows,
+    /// so the variance is exact or unknown
+    sum_sq: u64,
+    /// `sum_sq` is kept
+    squares: bool,
+    count: usize,
+    /// Exact histogram for quantiles, kept only in histogram mode
+    hist: Option<Box<Histogram>>,
 }
 
 impl TemperStat {
+    /// Restore statistics from raw values, e.g. loaded from a snapshot
+    pub const fn from_raw(min: i16, max: i16, sum: i64, count: usize) -> Self {
+        TemperStat { min, max, sum, sum_sq: 0, squares: false, count, hist: None }
+    }
+
+    /// Attach sum of squares, e.g. loaded from a snapshot
+    pub const fn with_sum_squares(mut self, sum_sq: u64) -> Self {
+        self.sum_sq = sum_sq;
+        self.squares = true;
+        self
+    }
+
+    /// Attach histogram, e.g. loaded from a snapshot
+    pub fn with_histogram(mut self, hist: Histogram) -> Self {
+        self.hist = Some(Box::new(hist));
+        self
+    }
+
     pub fn from_i16(v: i16) -> Self {
         TemperStat {
             min: v,
             max: v,
             sum: v as i64,
-            count: 1
+            sum_sq: 0,
+            squares: false,
+            count: 1,
+            hist: None,
         }
     }
 
+    /// Statistics of one value in histogram mode
+    pub fn from_i16_with_histogram(v: i16) -> Self {
+        let mut hist = Histogram::new();
+        hist.add(v);
+        TemperStat { hist: Some(Box::new(hist)), ..Self::from_i16(v) }
+    }
+
+    /// Statistics of one value keeping the sum of squares with `options.variance`
+    /// and the histogram with `options.histogram`
+    pub fn from_i16_with(v: i16, options: ParseOptions) -> Self {
+        let s = if options.histogram { Self::from_i16_with_histogram(v) } else { Self::from_i16(v) };
+        if options.variance { s.with_sum_squares(square(v)) } else { s }
+    }
+
+    #[inline]
     pub fn update(&mut self, v: i16) {
         if self.min > v {
             self.min = v;
@@ -116,7 +184,13 @@ impl TemperStat {
             self.max = v;
         }
         self.sum += v as i64;
+        if self.squares {
+            self.add_squares(square(v));
+        }
         self.count += 1;
+        if let Some(h) = &mut self.hist {
+            h.add(v);
+        }
     }
 
     pub fn merge(&mut self, other: &Self) {
@@ -127,14 +201,116 @@ impl TemperStat {
             self.max = other.max
         }
         self.sum += other.sum;
+        // the variance stays exact only if all merged statistics have sums of squares
+        self.squares &= other.squares;
+        if self.squares {
+            self.add_squares(other.sum_sq);
+        }
         self.count += other.count;
+        // quantiles stay exact only if all merged statistics have histograms
+        match (&mut self.hist, &other.hist) {
+            (Some(h), Some(o)) => h.merge(o),
+            (Some(_), None) => self.hist = None,
+            _ => {}
+        }
+    }
+
+    #[inline]
+    fn add_squares(&mut self, sq: u64) {
+        match self.sum_sq.checked_add(sq) {
+            Some(sum_sq) => self.sum_sq = sum_sq,
+            None => self.squares = false,
+        }
+    }
+
+    /// Minimum in tenths of degree
+    pub fn min(&self) -> i16 {
+        self.min
+    }
+
+    /// Maximum in tenths of degree
+    pub fn max(&self) -> i16 {
+        self.max
+    }
+
+    /// Sum in tenths of degree
+    pub fn sum(&self) -> i64 {
+        self.sum
+    }
+
+    /// Sum of squares in hundredths of squared degree, `None` if not kept or overflowed
+    pub fn sum_squares(&self) -> Option<u64> {
+        self.squares.then_some(self.sum_sq)
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Mean in degrees
+    pub fn mean(&self) -> f64 {
+        self.sum as f64 / (self.count as f64 * 10.0)
+    }
+
+    /// Population variance in squared degrees, `n * sum_sq - sum^2` is computed exactly.
+    /// `None` without sum of squares.
+    pub fn variance(&self) -> Option<f64> {
+        let sum_sq = self.sum_squares()?;
+        let n = self.count as u128;
+        let d = (n * sum_sq as u128).saturating_sub(self.sum.unsigned_abs() as u128 * self.sum.unsigned_abs() as u128);
+        Some(d as f64 / (n * n * 100) as f64)
+    }
+
+    /// Population standard deviation in degrees, `None` without sum of squares
+    pub fn std_dev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+
+    pub fn histogram(&self) -> Option<&Histogram> {
+        self.hist.as_deref()
     }
+
+    /// Exact quantile `q` in `0.0..=1.0` in tenths of degree, `None` without histogram
+    pub fn quantile(&self, q: f64) -> Option<i16> {
+        self.hist.as_ref().and_then(|h| h.quantile(q))
+    }
+
+    /// Exact median in tenths of degree, `None` without histogram
+    pub fn median(&self) -> Option<i16> {
+        self.quantile(0.5)
+    }
+}
+
+#[inline(always)]
+fn square(v: i16) -> u64 {
+    (v as i32 * v as i32) as u64
 }
 
 impl Display for TemperStat {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:.1}/{:.1}/{:.1}", self.min as f32 / 10.0, self.sum as f64 / (self.count as f64 * 10.0), self.max as f32 / 10.0)
+        write!(f, "{:.1}/{:.1}/{:.1}", self.min as f32 / 10.0, self.mean(), self.max as f32 / 10.0)
+    }
+}
+
+/// Exact decimal of integer tenths, `-15` is displayed as `-1.5`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tenths(pub i64);
+
+impl Display for Tenths {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{sign}{}.{}", abs / 10, abs % 10)
+    }
+}
+
+#[test]
+fn test_tenths() {
+    for i in -999..=999 {
+        assert_eq!(format!("{:.1}", i as f64 / 10.0), Tenths(i).to_string());
     }
+    assert_eq!("-0.5", Tenths(-5).to_string());
+    assert_eq!("99900000000.0", Tenths(999_000_000_000).to_string());
 }
 
 #[test]
@@ -154,6 +330,69 @@ fn test_temper_stat_i16() {
     dbg!(&a);
 }
 
+#[test]
+fn test_temper_stat_variance() {
+    let values: [i16; 6] = [19, 998, -105, -999, 0, 7];
+    let options = ParseOptions { variance: true, ..Default::default() };
+    let mut a = TemperStat::from_i16_with(values[0], options);
+    values[1..3].iter().for_each(|&v| a.update(v));
+    let mut b = TemperStat::from_i16_with(values[3], options);
+    values[4..].iter().for_each(|&v| b.update(v));
+    a.merge(&b);
+
+    let mean = values.iter().map(|&v| v as f64 / 10.0).sum::<f64>() / 6.0;
+    let variance = values.iter().map(|&v| (v as f64 / 10.0 - mean).powi(2)).sum::<f64>() / 6.0;
+    assert!((variance - a.variance().unwrap()).abs() < 1e-9, "{variance} {:?}", a.variance());
+    assert!((variance.sqrt() - a.std_dev().unwrap()).abs() < 1e-9);
+    assert_eq!(Some(0.0), TemperStat::from_i16_with(-999, options).variance());
+
+    // a billion extreme values don't overflow
+    let mut s = TemperStat::from_raw(-999, 999, 0, 2_000_000_000).with_sum_squares(999 * 999 * 2_000_000_000);
+    s.merge(&s.clone());
+    assert!((9980.01 - s.variance().unwrap()).abs() < 1e-9);
+
+    // an overflowed sum of squares is dropped instead of clamped
+    let mut big = TemperStat::from_raw(-999, 999, 0, 2).with_sum_squares(u64::MAX - 40);
+    big.update(5);
+    assert_eq!(Some(u64::MAX - 15), big.sum_squares());
+    big.update(5);
+    assert_eq!(None, big.sum_squares());
+    assert_eq!(None, big.variance());
+    big.update(0);
+    assert_eq!(None, big.sum_squares());
+
+    // without sums of squares the variance is unknown, also after merge with one
+    let mut plain = TemperStat::from_i16(5);
+    plain.update(7);
+    assert_eq!(None, plain.sum_squares());
+    assert_eq!(None, plain.variance());
+    a.merge(&plain);
+    assert_eq!(None, a.std_dev());
+}
+
+#[test]
+fn test_temper_stat_histogram() {
+    let mut a = TemperStat::from_i16_with_histogram(-999);
+    (-998..=999).for_each(|v| a.update(v));
+    assert_eq!(Some(0), a.median());
+    assert_eq!(Some(-900), a.quantile(0.05));
+    assert_eq!(Some(999), a.quantile(1.0));
+
+    let mut b = TemperStat::from_i16_with_histogram(0);
+    b.update(0);
+    a.merge(&b);
+    assert_eq!(2001, a.histogram().unwrap().count());
+    assert_eq!(Some(0), a.median());
+
+    // without histogram quantiles are unknown, also after merge with one
+    let mut plain = TemperStat::from_i16(5);
+    assert_eq!(None, plain.median());
+    plain.merge(&b);
+    assert_eq!(None, plain.median());
+    a.merge(&TemperStat::from_i16(5));
+    assert_eq!(None, a.median());
+}
+
 /// The rounding must be done using the semantics of IEEE 754 rounding-direction "roundTowardPositive",
 /// one digit after decimal point.
 pub fn temp_round(x: f64) -> f32 {
@@ -163,54 +402,157 @@ pub fn temp_round(x: f64) -> f32 {
     (r / 10.0) as f32
 }
 
+/// Line parsing options
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Check station names by `validate_name`. The number of stations is checked on the
+    /// whole result by `Driver` and `merge_snapshots`, see `TooManyStations`.
+    pub strict: bool,
+    /// Collect exact histograms for quantiles
+    pub histogram: bool,
+    /// Keep sums of squares for the variance
+    pub variance: bool,
+}
+
 /// Parse block of lines to map of stations name and temperature statistics, parsed line counter, errors counter
-pub fn process_block(block: &[u8], capacity: usize) -> (AHashMap<&[u8],TemperStat>, usize, usize) {
-    let mut map: AHashMap<&[u8],TemperStat> = AHashMap::with_capacity(capacity);
+pub fn process_block<A: Aggregator>(block: &[u8], capacity: usize) -> (StationTable<'_, A>, usize, usize) {
+    let (map, stats) = process_block_stats(block, capacity, ParseOptions::default(), &mut Diagnostics::default());
+    (map, stats.lines, stats.rejected())
+}
 
-    let mut line_count: usize = 0;
-    let mut error_count: usize = 0;
+/// Check station name constraints: 1..=`STATION_NAME_MAX_LEN` bytes of UTF-8 without `;`
+pub fn validate_name(name: &[u8]) -> Option<ParseError> {
+    if name.is_empty() {
+        Some(ParseError::EmptyName)
+    } else if name.len() > STATION_NAME_MAX_LEN {
+        Some(ParseError::NameTooLong)
+    } else if memchr(b';', name).is_some() {
+        Some(ParseError::DelimiterInName)
+    } else if std::str::from_utf8(name).is_err() {
+        Some(ParseError::InvalidUtf8)
+    } else {
+        None
+    }
+}
 
-    let mut begin: usize = 0;
+/// Parse block of lines to map of stations name and temperature statistics, line counters by error reason.
+/// Rejected lines are sampled into `diagnostics`.
+///
+/// In `strict` mode station names are checked by `validate_name` too. The check is done only
+/// for names not yet in the map, so repeated stations don't pay for it.
+pub fn process_block_stats<'a, A: Aggregator>(block: &'a [u8], capacity: usize, options: ParseOptions, diagnostics: &mut Diagnostics<'a>) -> (StationTable<'a, A>, RunStats) {
+    let mut map = StationTable::new(capacity);
+    let stats = process_block_into(block, &mut map, options, diagnostics);
+    (map, stats)
+}
 
-    let mut m = memchr_iter(b'\n', block);
+/// Same as `process_block_stats`, but updates existing `map`, e.g. kept by a worker for all its chunks
+pub fn process_block_into<'a, A: Aggregator>(block: &'a [u8], map: &mut StationTable<'a, A>, options: ParseOptions, diagnostics: &mut Diagnostics<'a>) -> RunStats {
+    process_block_with(Backend::active(), block, map, options, diagnostics)
+}
 
-    while let Some(end) = m.next() {
-        let line = &block[begin..end];
+/// Same as `process_block_into` with the given backend instead of the active one.
+/// The backend is dispatched once per block, a backend not supported by the CPU falls back to scalar.
+pub fn process_block_with<'a, A: Aggregator>(backend: Backend, block: &'a [u8], map: &mut StationTable<'a, A>, options: ParseOptions, diagnostics: &mut Diagnostics<'a>) -> RunStats {
+    match backend {
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse2 => if let Some(masks) = backend::Sse2Masks::new() {
+            return process_records(Records::with_masks(block, masks), block, map, options, diagnostics);
+        },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => if let Some(masks) = backend::Avx2Masks::new() {
+            // SAFETY: `masks` exist only when AVX2 is detected
+            return unsafe { process_records_avx2(Records::with_masks(block, masks), block, map, options, diagnostics) };
+        },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => if let Some(masks) = backend::NeonMasks::new() {
+            return process_records(Records::with_masks(block, masks), block, map, options, diagnostics);
+        },
+        _ => {}
+    }
+    process_records(Records::new(block), block, map, options, diagnostics)
+}
 
-        line_count += 1;
-        begin = end + 1;
+/// `process_records` compiled with AVX2 enabled, so the AVX2 masks are inlined into the loop
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn process_records_avx2<'a, A: Aggregator>(records: Records<'a, backend::Avx2Masks>, block: &'a [u8], map: &mut StationTable<'a, A>, options: ParseOptions, diagnostics: &mut Diagnostics<'a>) -> RunStats {
+    process_records(records, block, map, options, diagnostics)
+}
 
-        if let Some(col) = memrchr(b';', line) {
-            let (name, temp) = (&line[0..col], &line[(col + 1)..]);
-            if let Some(t) = i16_from_bytes(temp) {
-                if let Some(c) = map.get_mut(name) {
-                    c.update(t);
-                } else {
-                    map.insert(name, TemperStat::from_i16(t));
-                }
-            } else {
-                // let str = from_utf8(temp).unwrap_or("$$$$");
-                // eprintln!("Incorrect temperature `{}` in line {line_count}", str);
-                error_count += 1;
-                continue
-            }
-        } else {
-            // eprintln!("No column delimiter in line {line_count}");
-            error_count += 1;
+#[inline(always)]
+fn process_records<'a, A: Aggregator, M: Masks>(records: Records<'a, M>, block: &'a [u8], map: &mut StationTable<'a, A>, options: ParseOptions, diagnostics: &mut Diagnostics<'a>) -> RunStats {
+    let mut stats = RunStats::new();
+
+    for (name, temp) in records {
+        let offset = name.as_ptr() as usize - block.as_ptr() as usize;
+        stats.lines += 1;
+
+        let Some(temp) = temp else {
+            stats.reject(ParseError::MissingDelimiter);
+            diagnostics.push(offset, name, ParseError::MissingDelimiter);
             continue
+        };
+        let line = &block[offset..offset + name.len() + 1 + temp.len()];
+
+        let Some(t) = i16_from_bytes_swar(temp) else {
+            let e = temperature_error(temp);
+            stats.reject(e);
+            diagnostics.push(offset, line, e);
+            continue
+        };
+        let insert = |name: &[u8]| match options.strict.then(|| validate_name(name)).flatten() {
+            Some(e) => Err(e),
+            None => Ok(A::init_with(t, options)),
+        };
+        if let Err(e) = map.update(name, hash_name(name), t, insert) {
+            stats.reject(e);
+            diagnostics.push(offset, line, e);
         }
     }
 
-    (map, line_count, error_count)
+    stats
 }
 
 #[test]
 fn test_process_block() {
     let block = test::TEST_STR_100.as_bytes();
-    let (map, lines, errors) = process_block(block, 100);
+    let (map, lines, errors) = process_block::<TemperStat>(block, 100);
     assert_eq!(90, map.len());
     assert_eq!(100, lines);
     assert_eq!(0, errors);
+
+    let block = b"Brussels;14.9\nBrussels\nBrussels;1e1\nBrussels;-1.0\n";
+    let mut diagnostics = Diagnostics::new(100, 10);
+    let (map, stats) = process_block_stats::<TemperStat>(block, 10, ParseOptions::default(), &mut diagnostics);
+    assert_eq!(1, map.len());
+    assert_eq!(4, stats.lines);
+    assert_eq!(2, stats.accepted());
+    assert_eq!(1, stats.errors(ParseError::MissingDelimiter));
+    assert_eq!(1, stats.errors(ParseError::BadTemperature));
+    assert_eq!(2, diagnostics.samples().len());
+    assert_eq!(114, diagnostics.samples()[0].offset);
+    assert_eq!(b"Brussels;1e1", diagnostics.samples()[1].line);
+}
+
+#[test]
+fn test_process_block_strict() {
+    let long = format!("{};1.0\n", "x".repeat(STATION_NAME_MAX_LEN + 1));
+    let block = [&b"Brussels;14.9\n;1.0\nBrus;sels;1.0\n\xff\xfe;1.0\n"[..], long.as_bytes()].concat();
+
+    let (map, stats) = process_block_stats::<TemperStat>(&block, 10, ParseOptions::default(), &mut Diagnostics::default());
+    assert_eq!(5, map.len());
+    assert_eq!(0, stats.rejected());
+
+    let (map, stats) = process_block_stats::<TemperStat>(&block, 10, ParseOptions { strict: true, ..Default::default() }, &mut Diagnostics::default());
+    assert_eq!(1, map.len());
+    assert_eq!(5, stats.lines);
+    for e in [ParseError::EmptyName, ParseError::DelimiterInName, ParseError::InvalidUtf8, ParseError::NameTooLong] {
+        assert_eq!(1, stats.errors(e), "{e}");
+    }
+
+    let name = "愛".repeat(STATION_NAME_MAX_LEN / 3);
+    assert_eq!(None, validate_name(name.as_bytes()));
 }
 
 pub mod test {
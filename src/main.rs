@@ -1,65 +1,214 @@
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
+use std::io::{stdout, BufWriter, Write};
 use std::path::Path;
-use std::sync::mpsc::channel;
-use std::{thread, thread::available_parallelism};
+use std::process::exit;
+use std::thread::available_parallelism;
 use std::time::Instant;
-use ahash::AHashMap;
-use memmap::Mmap;
-use one_brc::{FILE_PATH, LINE_MAX_LEN, process_block, result::TemperStatResult};
+use one_brc::{TemperStat, result::TemperStatResult};
+use one_brc::backend::{Backend, BACKEND_ENV};
+use one_brc::cli::{Args, Command, OutputFormat, USAGE};
+use one_brc::diagnostics::Diagnostics;
+use one_brc::driver::{Aggregation, Driver, Inputs};
+use one_brc::input::expand_globs;
+use one_brc::json::{write_json_string, JsonLayout};
+use one_brc::snapshot::{merge_snapshots, SNAPSHOT_HISTOGRAM, SNAPSHOT_STRICT, SNAPSHOT_VARIANCE};
+use one_brc::stats::{ErrorAction, TooManyStations};
 
 fn main() {
     let start = Instant::now();
 
-    let cpu = available_parallelism().unwrap();
-    let file = File::open(Path::new(FILE_PATH)).unwrap();
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("one-brc: {e}\n\n{USAGE}");
+            exit(2);
+        }
+    };
+    if args.help {
+        print!("{USAGE}");
+        return;
+    }
+
+    let backend = match args.backend {
+        Some(b) => Backend::set_active(b),
+        None => Backend::from_env().map(|_| ()).map_err(|e| format!("{BACKEND_ENV}: {e}")),
+    };
+    if let Err(e) = backend {
+        eprintln!("one-brc: {e}");
+        exit(2);
+    }
 
-    let mmap = unsafe { Mmap::map(&file).unwrap() };
-    let len = mmap.len();
-    let size = len / cpu + LINE_MAX_LEN;
+    match args.command {
+        Command::Aggregate => aggregate(&args),
+        Command::Merge => merge(&args),
+    }
 
-    thread::scope(|s| {
-        let mut rest = mmap.as_ref();
+    eprintln!("elapsed: {:?}", start.elapsed());
+}
 
-        let mut threads= AHashMap::new();
+/// Process measurement files and write the result
+fn aggregate(args: &Args) {
+    let cpu = args.threads.unwrap_or_else(|| available_parallelism().unwrap());
 
-        let (tx, rx) = channel::<i32>();
+    let paths = expand_globs(&args.inputs).unwrap_or_else(|e| {
+        eprintln!("one-brc: {e}");
+        exit(2);
+    });
 
-        let mut result = TemperStatResult::new();
+    let mut inputs = Inputs::open(&paths).unwrap_or_else(|e| {
+        eprintln!("one-brc: {e}");
+        exit(1);
+    });
+    if args.diagnostics > 0 {
+        for path in inputs.not_mapped() {
+            eprintln!("one-brc: {}: diagnostics are not collected for streams and compressed files", path.display());
+        }
+    }
 
-        let mut id = 0;
-        while !rest.is_empty() {
-            let mut l = if rest.len() > size { size } else { rest.len() };
-            let mut i = l;
-            while i > 0 && rest[i - 1] != b'\n' {
-                i -= 1;
-            }
-            if i != 0 { l = i };
-            let (cur, tail) = rest.split_at(l);
-
-            let h = s.spawn({
-                let tx = tx.clone();
-                move || {
-                    let (map, lines, errors) = process_block(cur, 7000);
-                    tx.send(id).unwrap();
-                    (map, lines, errors)
-                }
-            });
-            threads.insert(id, h);
-            rest = tail;
-            id += 1;
+    let driver = Driver::new()
+        .threads(cpu)
+        .chunk_size(args.chunk_size)
+        .buffer_size(args.buffer_size)
+        .parse_options(args.parse_options())
+        .diagnostics(args.diagnostics)
+        .per_file(args.per_file);
+    let Aggregation { result, stats, files, data } = driver.process::<TemperStat>(&mut inputs).unwrap_or_else(|e| {
+        if TooManyStations::is(&e) {
+            eprintln!("one-brc: error: {e}");
+            exit(3);
         }
+        eprintln!("one-brc: {e}");
+        exit(1);
+    });
+
+    for ((path, file), data) in paths.iter().zip(files.iter()).zip(data) {
+        report(path, data, &file.diagnostics);
+        if args.stats && args.per_file {
+            eprintln!("{}: {}", path.display(), file.stats);
+        }
+    }
+
+    if args.stats {
+        eprintln!("files: {}, backend: {}, {stats}", files.len(), Backend::active());
+    }
 
-        while !threads.is_empty() {
-            let id = rx.recv().unwrap();
-            if let Some(h) = threads.remove(&id) {
-                if let Ok((map, _, _)) = h.join() {
-                    result.aggregate(&map);
-                }
+    if args.errors.exceeded(&stats) {
+        match args.errors.action {
+            ErrorAction::Ignore => {}
+            ErrorAction::Warn => eprintln!("one-brc: warning: rejected lines, {stats}"),
+            ErrorAction::Fail => {
+                eprintln!("one-brc: error: too many rejected lines, {stats}");
+                exit(3);
             }
         }
+    }
 
-        println!("{result}");
+    let per_file: Vec<(&Path, &TemperStatResult)> = match args.per_file {
+        true => paths.iter().zip(files.iter()).map(|(path, file)| (path.as_path(), &file.result)).collect(),
+        false => Vec::new(),
+    };
+    let flags = if args.strict { SNAPSHOT_STRICT } else { 0 }
+        | if args.extra.histogram() { SNAPSHOT_HISTOGRAM } else { 0 }
+        | if args.parse_options().variance { SNAPSHOT_VARIANCE } else { 0 };
+    write_result(&result, &per_file, args, flags);
+}
+
+/// Combine snapshots and write the result
+fn merge(args: &Args) {
+    let buffers: Vec<Vec<u8>> = args.inputs.iter().map(|path| {
+        fs::read(path).unwrap_or_else(|e| {
+            eprintln!("one-brc: {}: {e}", path.display());
+            exit(1);
+        })
+    }).collect();
+
+    let snapshots: Vec<&[u8]> = buffers.iter().map(Vec::as_slice).collect();
+    let (info, result) = merge_snapshots(&snapshots).unwrap_or_else(|e| {
+        match e.index {
+            Some(i) => eprintln!("one-brc: {}: {e}", args.inputs[i].display()),
+            None => eprintln!("one-brc: error: {e}"),
+        }
+        exit(3);
     });
 
-    eprintln!("elapsed: {:?}", start.elapsed());
+    let flags = info.flags;
+    if args.stats {
+        eprintln!("snapshots: {}, stations: {}", args.inputs.len(), result.len());
+    }
+    if args.extra.histogram() && flags & SNAPSHOT_HISTOGRAM == 0 {
+        eprintln!("one-brc: error: quantiles require snapshots written with histograms, e.g. with `--percentiles`");
+        exit(3);
+    }
+    if args.parse_options().variance && flags & SNAPSHOT_VARIANCE == 0 {
+        eprintln!("one-brc: error: variance requires snapshots written with sums of squares, e.g. with `--variance`");
+        exit(3);
+    }
+
+    write_result(&result, &[], args, flags);
+}
+
+/// Print rejected line samples with line numbers to stderr
+fn report(path: &Path, data: &[u8], diagnostics: &Diagnostics) {
+    let numbers = diagnostics.line_numbers(data);
+    for (d, line) in diagnostics.samples().iter().zip(numbers) {
+        eprintln!("{}:{line}: {d}", path.display());
+    }
+    if !diagnostics.samples().is_empty() && diagnostics.dropped() > 0 {
+        eprintln!("{}: {} more rejected lines not shown", path.display(), diagnostics.dropped());
+    }
+}
+
+fn write_result(result: &TemperStatResult, per_file: &[(&Path, &TemperStatResult)], args: &Args, flags: u32) {
+    if let Err(e) = write_output(result, per_file, args, flags) {
+        eprintln!("one-brc: write result: {e}");
+        exit(1);
+    }
+}
+
+/// Write the combined result, preceded by the `per_file` results if any.
+/// Text formats separate results by `# <path>` and `# total` lines, JSON formats nest them
+/// into `{"files":{"<path>":...},"total":...}`.
+fn write_output(result: &TemperStatResult, per_file: &[(&Path, &TemperStatResult)], args: &Args, flags: u32) -> std::io::Result<()> {
+    let mut writer: Box<dyn Write> = match args.output.as_deref() {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(stdout().lock())),
+    };
+
+    let json = matches!(args.format, OutputFormat::Json | OutputFormat::JsonObject);
+    if !per_file.is_empty() {
+        if json { writer.write_all(b"{\"files\":{")?; }
+        for (c, (path, r)) in per_file.iter().enumerate() {
+            if json {
+                if c != 0 { writer.write_all(b",")?; }
+                write_json_string(&mut writer, path.as_os_str().as_encoded_bytes())?;
+                writer.write_all(b":")?;
+            } else {
+                writeln!(writer, "# {}", path.display())?;
+            }
+            write_one(&mut writer, r, args, flags)?;
+        }
+        if json { writer.write_all(b"},\"total\":")?; } else { writeln!(writer, "# total")?; }
+    }
+    write_one(&mut writer, result, args, flags)?;
+    if json {
+        if !per_file.is_empty() { writer.write_all(b"}")?; }
+        writeln!(writer)?;
+    }
+
+    writer.flush()
+}
+
+/// Write one result, JSON documents are written without trailing line break
+fn write_one(writer: &mut dyn Write, result: &TemperStatResult, args: &Args, flags: u32) -> std::io::Result<()> {
+    match args.format {
+        OutputFormat::Brc => {
+            result.write_brc(&mut *writer, &args.extra)?;
+            writeln!(writer)
+        }
+        OutputFormat::Json => result.write_json(writer, JsonLayout::Array, &args.extra),
+        OutputFormat::JsonObject => result.write_json(writer, JsonLayout::Object, &args.extra),
+        OutputFormat::Csv | OutputFormat::Tsv => result.write_csv(writer, &args.csv),
+        OutputFormat::Snapshot => result.write_snapshot(writer, flags),
+    }
 }
@@ -0,0 +1,186 @@
+//! Parallel processing of memory mapped files.
+//!
+//! Files are cut into chunks of `chunk_size` bytes, the workers take the next chunk from
+//! a shared atomic cursor until all chunks are taken, so a slow core processes fewer chunks
+//! instead of delaying the whole run. Chunk boundaries are moved to line boundaries by the
+//! worker itself: a chunk owns the lines starting inside it.
+//!
+//! Every worker keeps one map for all its chunks. Chunks are taken in file order, so a worker
+//! which moves to the next file never returns to the previous one: per-file results are merged
+//! into the file result at that moment and the map is reused, otherwise the map collects all
+//! files and is aggregated when the worker finishes.
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use memchr::memchr;
+use crate::aggregator::Aggregator;
+use crate::diagnostics::Diagnostics;
+use crate::result::TemperStatResult;
+use crate::stats::RunStats;
+use crate::{process_block_into, ParseOptions, TemperStat};
+use crate::table::StationTable;
+
+/// Default chunk size taken by a worker at once
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Result of one input file
+#[derive(Debug, Default)]
+pub struct FileResult<'a, A = TemperStat> {
+    pub result: TemperStatResult<'a, A>,
+    pub stats: RunStats,
+    /// Up to `samples` rejected lines with the lowest offsets
+    pub diagnostics: Diagnostics<'a>,
+}
+
+/// Lines starting in `data[begin..end]`, the last line may end after `end`
+fn chunk_lines(data: &[u8], begin: usize, end: usize) -> &[u8] {
+    let line_begin = |pos: usize| match pos {
+        0 => 0,
+        pos if pos >= data.len() => data.len(),
+        pos => memchr(b'\n', &data[pos - 1..]).map_or(data.len(), |i| pos + i),
+    };
+    let (begin, end) = (line_begin(begin), line_begin(end));
+    if begin < end { &data[begin..end] } else { &[] }
+}
+
+/// Map of a worker and counters of the file it processes
+struct Worker<'a, A> {
+    map: StationTable<'a, A>,
+    file: Option<usize>,
+    stats: RunStats,
+    diagnostics: Diagnostics<'a>,
+    samples: usize,
+}
+
+impl<'a, A: Aggregator> Worker<'a, A> {
+    /// Add counters of the current file to its result, and the map if `per_file`
+    fn flush(&mut self, results: &[Mutex<FileResult<'a, A>>], per_file: bool) {
+        let Some(file) = self.file.take() else { return };
+        let mut r = results[file].lock().unwrap();
+        r.stats.merge(&std::mem::take(&mut self.stats));
+        r.diagnostics.merge(std::mem::replace(&mut self.diagnostics, Diagnostics::new(0, self.samples)));
+        if per_file {
+            r.result.aggregate(&self.map);
+            self.map.clear();
+        }
+    }
+}
+
+/// Process files by `threads` workers taking chunks of `chunk_size` bytes, with the result of every file
+pub fn process_files<'a, A: Aggregator + Send>(files: &[&'a [u8]], threads: NonZeroUsize, chunk_size: usize, options: ParseOptions, samples: usize) -> Vec<FileResult<'a, A>> {
+    process(files, threads, chunk_size, options, samples, true).1
+}
+
+/// Same as `process_files`, but only the combined result of all files is collected.
+/// File results have line counters and diagnostics, without station statistics.
+pub fn process_files_combined<'a, A: Aggregator + Send>(files: &[&'a [u8]], threads: NonZeroUsize, chunk_size: usize, options: ParseOptions, samples: usize) -> (TemperStatResult<'a, A>, Vec<FileResult<'a, A>>) {
+    process(files, threads, chunk_size, options, samples, false)
+}
+
+fn process<'a, A: Aggregator + Send>(files: &[&'a [u8]], threads: NonZeroUsize, chunk_size: usize, options: ParseOptions, samples: usize, per_file: bool) -> (TemperStatResult<'a, A>, Vec<FileResult<'a, A>>) {
+    let chunk_size = chunk_size.max(1);
+
+    // first global chunk index of every file and after the last file
+    let mut first_chunk = Vec::with_capacity(files.len() + 1);
+    let mut chunks = 0;
+    for data in files {
+        first_chunk.push(chunks);
+        chunks += data.len().div_ceil(chunk_size);
+    }
+    first_chunk.push(chunks);
+
+    let cursor = AtomicUsize::new(0);
+    let results: Vec<Mutex<FileResult<'a, A>>> = files.iter()
+        .map(|_| Mutex::new(FileResult { diagnostics: Diagnostics::new(0, samples), ..FileResult::default() }))
+        .collect();
+    let total = Mutex::new(TemperStatResult::new());
+
+    thread::scope(|s| {
+        for _ in 0..threads.get().min(chunks) {
+            s.spawn(|| {
+                let mut worker = Worker { map: StationTable::new(0), file: None, stats: RunStats::new(), diagnostics: Diagnostics::new(0, samples), samples };
+
+                loop {
+                    let c = cursor.fetch_add(1, Ordering::Relaxed);
+                    if c >= chunks { break }
+                    let file = first_chunk.partition_point(|&f| f <= c) - 1;
+                    if worker.file != Some(file) {
+                        worker.flush(&results, per_file);
+                        worker.file = Some(file);
+                    }
+                    let data = files[file];
+                    let begin = (c - first_chunk[file]) * chunk_size;
+                    let lines = chunk_lines(data, begin, begin + chunk_size);
+                    if lines.is_empty() { continue }
+
+                    if worker.map.is_empty() { worker.map.reserve(7000) }
+                    let base = lines.as_ptr() as usize - data.as_ptr() as usize;
+                    let mut d = Diagnostics::new(base, samples);
+                    worker.stats.merge(&process_block_into(lines, &mut worker.map, options, &mut d));
+                    worker.diagnostics.merge(d);
+                }
+                worker.flush(&results, per_file);
+                if !per_file {
+                    total.lock().unwrap().aggregate(&worker.map);
+                }
+            });
+        }
+    });
+
+    (total.into_inner().unwrap(), results.into_iter().map(|r| r.into_inner().unwrap()).collect())
+}
+
+#[test]
+fn test_chunk_lines() {
+    let data = b"a;1.0\nbb;2.0\nccc;3.0";
+    assert_eq!(b"a;1.0\n", chunk_lines(data, 0, 6));
+    assert_eq!(b"a;1.0\nbb;2.0\n", chunk_lines(data, 0, 7));
+    assert_eq!(b"bb;2.0\n", chunk_lines(data, 6, 8));
+    assert_eq!(b"", chunk_lines(data, 7, 12));
+    assert_eq!(b"ccc;3.0", chunk_lines(data, 8, 40));
+    assert_eq!(b"", chunk_lines(data, 40, 80));
+
+    // every line is owned by exactly one chunk for any chunk size
+    for size in 1..data.len() + 2 {
+        let joined: Vec<u8> = (0..data.len()).step_by(size).flat_map(|b| chunk_lines(data, b, b + size).to_vec()).collect();
+        assert_eq!(&data[..], &joined[..]);
+    }
+}
+
+#[test]
+fn test_process_files() {
+    use crate::process_block;
+    use crate::test::TEST_STR_100;
+
+    let a = TEST_STR_100.repeat(3);
+    let b = "Brussels;1.0\nbroken\n".repeat(10);
+    let files = [a.as_bytes(), b.as_bytes()];
+
+    for (threads, chunk_size) in [(1, CHUNK_SIZE), (4, 64), (3, 1)] {
+        let results = process_files::<TemperStat>(&files, NonZeroUsize::new(threads).unwrap(), chunk_size, ParseOptions::default(), 3);
+        assert_eq!(2, results.len());
+        for (data, r) in files.iter().zip(results.iter()) {
+            let (m, lines, errors) = process_block::<TemperStat>(data, 100);
+            let mut expected = TemperStatResult::new();
+            expected.aggregate(&m);
+            assert_eq!(expected.to_string(), r.result.to_string());
+            assert_eq!((lines, errors), (r.stats.lines, r.stats.rejected()));
+        }
+        assert!(results[0].diagnostics.is_empty());
+        let offsets: Vec<usize> = results[1].diagnostics.samples().iter().map(|d| d.offset).collect();
+        assert_eq!(vec![13, 33, 53], offsets);
+        assert_eq!(7, results[1].diagnostics.dropped());
+
+        // the combined result only, with the same counters
+        let (total, combined) = process_files_combined::<TemperStat>(&files, NonZeroUsize::new(threads).unwrap(), chunk_size, ParseOptions::default(), 3);
+        let mut expected = TemperStatResult::new();
+        results.iter().for_each(|r| expected.merge(&r.result));
+        assert_eq!(expected.to_string(), total.to_string());
+        for (r, c) in results.iter().zip(combined.iter()) {
+            assert!(c.result.is_empty());
+            assert_eq!(r.stats, c.stats);
+            assert_eq!(r.diagnostics.samples(), c.diagnostics.samples());
+        }
+    }
+}
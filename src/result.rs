@@ -1,38 +1,137 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
 use std::str::from_utf8;
-use ahash::AHashMap;
-use crate::TemperStat;
+use crate::{TemperStat, Tenths};
+use crate::aggregator::Aggregator;
+use crate::histogram::Quantile;
+use crate::table::StationTable;
+
+/// Optional statistics written after the default ones
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtraStats {
+    /// Population variance and standard deviation
+    pub variance: bool,
+    /// Exact quantiles, require histograms
+    pub quantiles: Vec<Quantile>,
+}
+
+impl ExtraStats {
+    /// Statistics need histograms to be collected
+    pub fn histogram(&self) -> bool {
+        !self.quantiles.is_empty()
+    }
+}
 
 /// **Temper**ature **Stat**istic **Result**
-#[derive(Debug)]
-pub struct TemperStatResult<'a> {
-    r : BTreeMap<&'a[u8], TemperStat>
+///
+/// Station names are borrowed from the processed data, or owned when the data doesn't
+/// outlive the result, e.g. stream buffers.
+#[derive(Debug, Default)]
+pub struct TemperStatResult<'a, A = TemperStat> {
+    r : BTreeMap<Cow<'a, [u8]>, A>
 }
 
-impl<'a> TemperStatResult<'a> {
+impl<'a, A: Aggregator> TemperStatResult<'a, A> {
     pub fn new() -> Self {
-        TemperStatResult { r: BTreeMap::new() }
+        Self::default()
     }
 
-    pub fn aggregate(&mut self, m: &AHashMap<&'a [u8], TemperStat>) {
-        m.iter().for_each(|(&s, t)| {
+    /// Number of unique stations
+    pub fn len(&self) -> usize {
+        self.r.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.r.is_empty()
+    }
+
+    /// Stations in alphabetical (byte) order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &A)> {
+        self.r.iter().map(|(k, v)| (k.as_ref(), v))
+    }
+
+    pub fn aggregate(&mut self, m: &StationTable<'a, A>) {
+        m.iter().for_each(|(s, t)| self.insert(s, t))
+    }
+
+    /// Aggregate table copying station names, so the result doesn't borrow the table keys
+    pub fn aggregate_owned(&mut self, m: &StationTable<'_, A>) {
+        m.iter().for_each(|(s, t)| {
             if let Some(v) = self.r.get_mut(s) {
                 v.merge(t);
             } else {
-                self.r.insert(s, t.clone());
+                self.r.insert(Cow::Owned(s.to_vec()), t.clone());
+            }
+        })
+    }
+
+    /// Merge station statistics into the result
+    pub fn insert(&mut self, s: &'a [u8], t: &A) {
+        if let Some(v) = self.r.get_mut(s) {
+            v.merge(t);
+        } else {
+            self.r.insert(Cow::Borrowed(s), t.clone());
+        }
+    }
+
+    /// Merge other result, e.g. loaded from a snapshot
+    pub fn merge(&mut self, other: &TemperStatResult<'a, A>) {
+        other.r.iter().for_each(|(s, t)| {
+            if let Some(v) = self.r.get_mut(s.as_ref()) {
+                v.merge(t);
+            } else {
+                self.r.insert(s.clone(), t.clone());
             }
         })
     }
+
+    /// Copy borrowed station names, so the result doesn't borrow the processed data
+    pub fn into_owned(self) -> TemperStatResult<'static, A> {
+        TemperStatResult { r: self.r.into_iter().map(|(k, v)| (Cow::Owned(k.into_owned()), v)).collect() }
+    }
+}
+
+impl TemperStatResult<'_> {
+    /// Write the result in the 1BRC format, extra statistics are appended to every station
+    /// as `min/mean/max/variance/stddev` with two digits after decimal point, `-` if the station
+    /// has no sum of squares, followed by quantiles with one digit, `-` if it has no histogram
+    pub fn write_brc<W: Write>(&self, mut w: W, extra: &ExtraStats) -> io::Result<()> {
+        if *extra == ExtraStats::default() {
+            return write!(w, "{self}");
+        }
+        write!(w, "{{")?;
+        for (c, (k, v)) in self.iter().enumerate() {
+            if let Ok(a) = from_utf8(k) {
+                if c != 0 { write!(w, ", ")?; }
+                write!(w, "{a}={v}")?;
+                if extra.variance {
+                    match (v.variance(), v.std_dev()) {
+                        (Some(v), Some(d)) => write!(w, "/{v:.2}/{d:.2}")?,
+                        _ => write!(w, "/-/-")?,
+                    }
+                }
+                for q in extra.quantiles.iter() {
+                    match v.quantile(q.fraction()) {
+                        Some(t) => write!(w, "/{}", Tenths(t as i64))?,
+                        None => write!(w, "/-")?,
+                    }
+                }
+            };
+        };
+        write!(w, "}}")
+    }
 }
 
-impl Display for TemperStatResult<'_> {
+impl<A: Aggregator> Display for TemperStatResult<'_, A> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{{")?;
-        for (c, (&k, v)) in self.r.iter().enumerate() {
+        for (c, (k, v)) in self.iter().enumerate() {
             if let Ok(a) = from_utf8(k) {
                 if c != 0 { write!(f, ", ")?; }
-                write!(f, "{a}={v}")?;
+                write!(f, "{a}=")?;
+                v.format(f)?;
             };
         };
         write!(f, "}}")
@@ -48,4 +147,35 @@ fn test() {
     let (m, l, e) = process_block(TEST_STR_100.as_bytes(), 100);
     r.aggregate(&m);
     println!("{l}\t{e}\n{r}");
+
+    let mut buf = Vec::new();
+    r.write_brc(&mut buf, &ExtraStats::default()).unwrap();
+    assert_eq!(r.to_string().as_bytes(), buf);
+
+    let (m, _) = crate::process_block_stats(b"a;1.0\na;3.0\nb;-1.5\n", 10, crate::ParseOptions { variance: true, ..Default::default() }, &mut Default::default());
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+    let mut buf = Vec::new();
+    r.write_brc(&mut buf, &ExtraStats { variance: true, ..Default::default() }).unwrap();
+    assert_eq!("{a=1.0/2.0/3.0/1.00/1.00, b=-1.5/-1.5/-1.5/0.00/0.00}", String::from_utf8(buf).unwrap());
+
+    // without sums of squares the variance is `-`
+    let (m, _, _) = process_block(b"a;1.0\na;3.0\n", 10);
+    let mut plain = TemperStatResult::new();
+    plain.aggregate(&m);
+    let mut buf = Vec::new();
+    plain.write_brc(&mut buf, &ExtraStats { variance: true, ..Default::default() }).unwrap();
+    assert_eq!("{a=1.0/2.0/3.0/-/-}", String::from_utf8(buf).unwrap());
+
+    let mut buf = Vec::new();
+    let extra = ExtraStats { quantiles: vec![Quantile::MEDIAN, "p99".parse().unwrap()], ..Default::default() };
+    r.write_brc(&mut buf, &extra).unwrap();
+    assert_eq!("{a=1.0/2.0/3.0/-/-, b=-1.5/-1.5/-1.5/-/-}", String::from_utf8(buf).unwrap());
+
+    let (m, _) = crate::process_block_stats(b"a;1.0\na;3.0\na;2.5\nb;-1.5\n", 10, crate::ParseOptions { histogram: true, ..Default::default() }, &mut Default::default());
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+    let mut buf = Vec::new();
+    r.write_brc(&mut buf, &extra).unwrap();
+    assert_eq!("{a=1.0/2.2/3.0/2.5/3.0, b=-1.5/-1.5/-1.5/-1.5/-1.5}", String::from_utf8(buf).unwrap());
 }
\ No newline at end of file
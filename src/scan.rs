@@ -0,0 +1,132 @@
+//! Fused scan of station lines.
+//!
+//! The data is scanned once by windows of 64 bytes: every window is turned into two bitmasks
+//! of `;` and `\n` positions by a backend's `Masks`, the lines and their delimiters are taken
+//! from the masks with bit operations.
+use crate::backend::{Masks, ScalarMasks, WINDOW};
+
+/// Iterator of lines of a block, yields `(name, Some(temp))` split at the last `;` of the line,
+/// or `(line, None)` for a line without `;`. As in `process_block`, bytes after the last `\n`
+/// are not a line.
+///
+/// The iterator is generic over the backend masks, so the masks are inlined into the scan.
+pub struct Records<'a, M = ScalarMasks> {
+    data: &'a [u8],
+    masks: M,
+    /// Offset of the current window
+    window: usize,
+    semi: u64,
+    nl: u64,
+    /// Begin of the current line
+    begin: usize,
+    /// Last `;` of the current line found so far
+    delimiter: Option<usize>,
+}
+
+impl<'a> Records<'a> {
+    /// Records scanned by the portable backend
+    pub fn new(data: &'a [u8]) -> Self {
+        Self::with_masks(data, ScalarMasks)
+    }
+}
+
+impl<'a, M: Masks> Records<'a, M> {
+    #[inline]
+    pub fn with_masks(data: &'a [u8], masks: M) -> Self {
+        let (semi, nl) = window_masks(data, 0, masks);
+        Records { data, masks, window: 0, semi, nl, begin: 0, delimiter: None }
+    }
+}
+
+impl<'a, M: Masks> Iterator for Records<'a, M> {
+    type Item = (&'a [u8], Option<&'a [u8]>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.nl != 0 {
+                let i = self.nl.trailing_zeros();
+                self.nl &= self.nl - 1;
+                // `;` before the line end
+                let before = self.semi & ((1 << i) - 1);
+                if before != 0 {
+                    self.delimiter = Some(self.window + 63 - before.leading_zeros() as usize);
+                    self.semi &= !before;
+                }
+
+                let end = self.window + i as usize;
+                let begin = std::mem::replace(&mut self.begin, end + 1);
+                return Some(match self.delimiter.take() {
+                    Some(d) => (&self.data[begin..d], Some(&self.data[d + 1..end])),
+                    None => (&self.data[begin..end], None),
+                });
+            }
+
+            if self.semi != 0 {
+                self.delimiter = Some(self.window + 63 - self.semi.leading_zeros() as usize);
+            }
+            self.window += WINDOW;
+            if self.window >= self.data.len() {
+                return None;
+            }
+            (self.semi, self.nl) = window_masks(self.data, self.window, self.masks);
+        }
+    }
+}
+
+#[inline]
+fn window_masks<M: Masks>(data: &[u8], offset: usize, masks: M) -> (u64, u64) {
+    match data.get(offset..offset + WINDOW) {
+        Some(w) => masks.masks(w.try_into().unwrap()),
+        None => {
+            // zero padded tail, zero is neither `;` nor `\n`
+            let mut w = [0u8; WINDOW];
+            let tail = &data[offset.min(data.len())..];
+            w[..tail.len()].copy_from_slice(tail);
+            masks.masks(&w)
+        }
+    }
+}
+
+#[test]
+fn test_records() {
+    use memchr::{memchr_iter, memrchr};
+    use crate::test::TEST_STR_100;
+
+    type Record<'a> = (&'a [u8], Option<&'a [u8]>);
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn collect<M: Masks>(data: &[u8], masks: Option<M>) -> Option<Vec<Record<'_>>> {
+        masks.map(|m| Records::with_masks(data, m).collect())
+    }
+
+    let long = format!("{};1.0\n{};2.0\n", "x".repeat(150), "y;".repeat(70));
+    let data = [TEST_STR_100, "a;b;1.0\nno delimiter\n;\n\n;;\n", &long, "Tail;1.0"].concat();
+    let data = data.as_bytes();
+
+    // every offset of the 64 bytes windows
+    for shift in 0..WINDOW {
+        let data = &data[shift..];
+        let mut expected: Vec<Record> = Vec::new();
+        let mut begin = 0;
+        for end in memchr_iter(b'\n', data) {
+            let line = &data[begin..end];
+            expected.push(match memrchr(b';', line) {
+                Some(d) => (&line[..d], Some(&line[d + 1..])),
+                None => (line, None),
+            });
+            begin = end + 1;
+        }
+        assert_eq!(expected, Records::new(data).collect::<Vec<_>>(), "scalar, shift {shift}");
+        #[cfg(target_arch = "x86_64")]
+        for records in [collect(data, crate::backend::Sse2Masks::new()), collect(data, crate::backend::Avx2Masks::new())] {
+            assert!(records.is_none_or(|r| r == expected), "shift {shift}");
+        }
+        #[cfg(target_arch = "aarch64")]
+        assert!(collect(data, crate::backend::NeonMasks::new()).is_none_or(|r| r == expected), "shift {shift}");
+    }
+
+    assert_eq!(vec![(&b"a;b"[..], Some(&b"1.0"[..]))], Records::new(b"a;b;1.0\n").collect::<Vec<_>>());
+    assert_eq!(0, Records::new(b"").count());
+    assert_eq!(0, Records::new(b"a;1.0").count());
+}
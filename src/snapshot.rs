@@ -0,0 +1,402 @@
+//! Binary snapshot of `TemperStatResult` keeping exact integer statistics, so results of
+//! separate runs can be merged without loss.
+//!
+//! Layout, all numbers are little-endian:
+//! ```text
+//! magic    8  b"1BRCSNAP"
+//! version  2  SNAPSHOT_VERSION
+//! scale    2  values per degree, 10 for tenths
+//! flags    4  SNAPSHOT_STRICT, SNAPSHOT_HISTOGRAM, SNAPSHOT_VARIANCE
+//! stations 8
+//! station records:
+//!   name_len 2, name, min 2, max 2, sum 8, count 8
+//!   with SNAPSHOT_VARIANCE: sum_sq 8
+//!   with SNAPSHOT_HISTOGRAM: base 2, buckets 2, bucket counts 8 each
+//! crc32    4  of all preceding bytes
+//! ```
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Result, Write};
+use crc32fast::Hasher;
+use crate::histogram::Histogram;
+use crate::result::TemperStatResult;
+use crate::stats::TooManyStations;
+use crate::TemperStat;
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"1BRCSNAP";
+pub const SNAPSHOT_VERSION: u16 = 1;
+/// Temperatures are stored in tenths of degree
+pub const SNAPSHOT_SCALE: u16 = 10;
+/// Flag: the input was parsed in strict mode
+pub const SNAPSHOT_STRICT: u32 = 1;
+/// Flag: station records are followed by histograms
+pub const SNAPSHOT_HISTOGRAM: u32 = 2;
+/// Flag: station records have sums of squares
+pub const SNAPSHOT_VARIANCE: u32 = 4;
+
+const HEADER_LEN: usize = 8 + 2 + 2 + 4 + 8;
+
+/// Snapshot header fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub version: u16,
+    pub scale: u16,
+    pub flags: u32,
+    pub stations: u64,
+}
+
+impl SnapshotInfo {
+    pub fn new(flags: u32, stations: usize) -> Self {
+        SnapshotInfo { version: SNAPSHOT_VERSION, scale: SNAPSHOT_SCALE, flags, stations: stations as u64 }
+    }
+
+    /// Snapshots can be merged only if written with the same value scale and flags
+    pub fn check_compatible(&self, other: &SnapshotInfo) -> Result<()> {
+        if self.scale != other.scale {
+            return Err(invalid(&format!("value scale {} differs from {}", other.scale, self.scale)));
+        }
+        if self.flags != other.flags {
+            let strict = |f: u32| if f & SNAPSHOT_STRICT != 0 { "strict" } else { "not strict" };
+            if (self.flags ^ other.flags) & SNAPSHOT_STRICT != 0 {
+                return Err(invalid(&format!("{} snapshot can't be merged with {} one", strict(other.flags), strict(self.flags))));
+            }
+            if (self.flags ^ other.flags) & SNAPSHOT_HISTOGRAM != 0 {
+                return Err(invalid("snapshots with and without histograms can't be merged"));
+            }
+            if (self.flags ^ other.flags) & SNAPSHOT_VARIANCE != 0 {
+                return Err(invalid("snapshots with and without sums of squares can't be merged"));
+            }
+            return Err(invalid(&format!("flags {:#x} differ from {:#x}", other.flags, self.flags)));
+        }
+        Ok(())
+    }
+}
+
+/// Error of `merge_snapshots`
+#[derive(Debug)]
+pub struct MergeError {
+    /// Index of the snapshot which can't be loaded or merged, `None` for errors of the merged result
+    pub index: Option<usize>,
+    pub error: Error,
+}
+
+impl Display for MergeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Load and merge snapshots, all of them must be compatible with the first one.
+/// Merged strict snapshots may have at most `STATIONS_MAX` stations, see `TooManyStations`.
+/// Return header of the merged result.
+pub fn merge_snapshots<'a>(snapshots: &[&'a [u8]]) -> std::result::Result<(SnapshotInfo, TemperStatResult<'a>), MergeError> {
+    let mut result = TemperStatResult::new();
+    let mut first: Option<SnapshotInfo> = None;
+
+    for (index, data) in snapshots.iter().enumerate() {
+        let (info, r) = TemperStatResult::read_snapshot(data).and_then(|(info, r)| {
+            if let Some(f) = first { f.check_compatible(&info)?; }
+            Ok((info, r))
+        }).map_err(|error| MergeError { index: Some(index), error })?;
+        first.get_or_insert(info);
+        result.merge(&r);
+    }
+
+    let first = first.ok_or_else(|| MergeError { index: None, error: Error::new(ErrorKind::InvalidInput, "no snapshots to merge") })?;
+    if first.flags & SNAPSHOT_STRICT != 0 {
+        TooManyStations::check(result.len()).map_err(|error| MergeError { index: None, error })?;
+    }
+    Ok((SnapshotInfo::new(first.flags, result.len()), result))
+}
+
+/// Writer computing CRC32 of written bytes
+struct CrcWriter<W: Write> {
+    w: W,
+    crc: Hasher,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.w.write(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.w.flush()
+    }
+}
+
+impl<'a> TemperStatResult<'a> {
+    /// Write the result as binary snapshot, `flags` are stored in the header.
+    /// With `SNAPSHOT_HISTOGRAM` every station must have a histogram, with `SNAPSHOT_VARIANCE`
+    /// a sum of squares.
+    pub fn write_snapshot<W: Write>(&self, w: W, flags: u32) -> Result<()> {
+        let info = SnapshotInfo::new(flags, self.len());
+        let mut w = CrcWriter { w, crc: Hasher::new() };
+
+        w.write_all(SNAPSHOT_MAGIC)?;
+        w.write_all(&info.version.to_le_bytes())?;
+        w.write_all(&info.scale.to_le_bytes())?;
+        w.write_all(&info.flags.to_le_bytes())?;
+        w.write_all(&info.stations.to_le_bytes())?;
+
+        for (name, s) in self.iter() {
+            let len = u16::try_from(name.len()).map_err(|_| Error::new(ErrorKind::InvalidInput, "station name is too long"))?;
+            w.write_all(&len.to_le_bytes())?;
+            w.write_all(name)?;
+            w.write_all(&s.min().to_le_bytes())?;
+            w.write_all(&s.max().to_le_bytes())?;
+            w.write_all(&s.sum().to_le_bytes())?;
+            w.write_all(&(s.count() as u64).to_le_bytes())?;
+            if flags & SNAPSHOT_VARIANCE != 0 {
+                let sum_sq = s.sum_squares().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "station has no sum of squares"))?;
+                w.write_all(&sum_sq.to_le_bytes())?;
+            }
+            if flags & SNAPSHOT_HISTOGRAM != 0 {
+                let h = s.histogram().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "station has no histogram"))?;
+                w.write_all(&h.base().to_le_bytes())?;
+                w.write_all(&(h.counts().len() as u16).to_le_bytes())?;
+                for n in h.counts() {
+                    w.write_all(&n.to_le_bytes())?;
+                }
+            }
+        }
+
+        let crc = w.crc.clone().finalize();
+        w.w.write_all(&crc.to_le_bytes())
+    }
+
+    /// Load snapshot, station names are borrowed from `data`
+    pub fn read_snapshot(data: &'a [u8]) -> Result<(SnapshotInfo, Self)> {
+        if data.len() < HEADER_LEN + 4 || &data[..8] != SNAPSHOT_MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+
+        let (body, crc) = data.split_at(data.len() - 4);
+        let mut hasher = Hasher::new();
+        hasher.update(body);
+        if hasher.finalize().to_le_bytes() != crc {
+            return Err(invalid("snapshot checksum mismatch"));
+        }
+
+        let mut r = Reader { data: body, pos: 8 };
+        let info = SnapshotInfo {
+            version: u16::from_le_bytes(r.array()?),
+            scale: u16::from_le_bytes(r.array()?),
+            flags: u32::from_le_bytes(r.array()?),
+            stations: u64::from_le_bytes(r.array()?),
+        };
+        if info.version != SNAPSHOT_VERSION {
+            return Err(invalid(&format!("unsupported snapshot version {}", info.version)));
+        }
+        if info.scale != SNAPSHOT_SCALE {
+            return Err(invalid(&format!("unsupported value scale {}", info.scale)));
+        }
+
+        let mut result = TemperStatResult::new();
+        for _ in 0..info.stations {
+            let len = u16::from_le_bytes(r.array()?) as usize;
+            let name = r.take(len)?;
+            let min = i16::from_le_bytes(r.array()?);
+            let max = i16::from_le_bytes(r.array()?);
+            let sum = i64::from_le_bytes(r.array()?);
+            let count = u64::from_le_bytes(r.array()?) as usize;
+            if count == 0 || min > max {
+                return Err(invalid("invalid station statistics"));
+            }
+            let mut stat = TemperStat::from_raw(min, max, sum, count);
+            if info.flags & SNAPSHOT_VARIANCE != 0 {
+                stat = stat.with_sum_squares(u64::from_le_bytes(r.array()?));
+            }
+            if info.flags & SNAPSHOT_HISTOGRAM != 0 {
+                let base = i16::from_le_bytes(r.array()?);
+                let len = u16::from_le_bytes(r.array()?) as usize;
+                let counts = r.take(len * 8)?.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+                let hist = Histogram::from_raw(base, counts).ok_or_else(|| invalid("invalid station histogram"))?;
+                stat = stat.with_histogram(hist);
+            }
+            result.insert(name, &stat);
+        }
+        if r.pos != body.len() {
+            return Err(invalid("trailing bytes after snapshot records"));
+        }
+
+        Ok((info, result))
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.data.len()).ok_or_else(|| invalid("truncated snapshot"))?;
+        let s = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(s)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    use crate::process_block;
+    use crate::test::TEST_STR_100;
+
+    let (m, _, _) = process_block(TEST_STR_100.as_bytes(), 100);
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+
+    let mut buf = Vec::new();
+    r.write_snapshot(&mut buf, SNAPSHOT_STRICT).unwrap();
+
+    let (info, loaded) = TemperStatResult::read_snapshot(&buf).unwrap();
+    assert_eq!(SnapshotInfo::new(SNAPSHOT_STRICT, r.len()), info);
+    assert_eq!(r.to_string(), loaded.to_string());
+
+    // merge with itself doubles counts and sums, keeps min and max
+    let mut merged = TemperStatResult::new();
+    merged.merge(&loaded);
+    merged.merge(&r);
+    for ((_, a), (_, b)) in r.iter().zip(merged.iter()) {
+        assert_eq!((a.min(), a.max(), a.sum() * 2, a.count() * 2), (b.min(), b.max(), b.sum(), b.count()));
+    }
+
+    let mut broken = buf.clone();
+    broken[HEADER_LEN + 3] ^= 1;
+    assert!(TemperStatResult::read_snapshot(&broken).is_err());
+    assert!(TemperStatResult::read_snapshot(&buf[..buf.len() - 1]).is_err());
+    assert!(TemperStatResult::read_snapshot(b"1BRCSNAP").is_err());
+
+    let mut v2 = buf.clone();
+    v2[8..10].copy_from_slice(&2u16.to_le_bytes());
+    let n = v2.len() - 4;
+    let crc = crc32fast::hash(&v2[..n]);
+    v2[n..].copy_from_slice(&crc.to_le_bytes());
+    assert_eq!("unsupported snapshot version 2", TemperStatResult::read_snapshot(&v2).unwrap_err().to_string());
+}
+
+#[test]
+fn test_snapshot_variance() {
+    use crate::{process_block_stats, ParseOptions};
+    use crate::test::TEST_STR_100;
+
+    let data = TEST_STR_100.as_bytes();
+    let (m, _) = process_block_stats(data, 100, ParseOptions { variance: true, ..Default::default() }, &mut Default::default());
+    let mut r = TemperStatResult::new();
+    r.aggregate(&m);
+    let mut buf = Vec::new();
+    r.write_snapshot(&mut buf, SNAPSHOT_VARIANCE).unwrap();
+
+    // sums of squares are merged exactly
+    let (info, merged) = merge_snapshots(&[&buf, &buf]).unwrap();
+    assert_eq!(SNAPSHOT_VARIANCE, info.flags);
+    for ((_, a), (_, b)) in r.iter().zip(merged.iter()) {
+        assert_eq!(a.sum_squares().map(|s| s * 2), b.sum_squares());
+        assert_eq!(a.variance(), b.variance());
+    }
+
+    // without sums of squares the station records can't be written with the flag
+    let (m, _, _) = crate::process_block(data, 100);
+    let mut plain = TemperStatResult::new();
+    plain.aggregate(&m);
+    assert!(plain.write_snapshot(&mut Vec::new(), SNAPSHOT_VARIANCE).is_err());
+    let mut plain_buf = Vec::new();
+    plain.write_snapshot(&mut plain_buf, 0).unwrap();
+    let e = merge_snapshots(&[&buf, &plain_buf]).unwrap_err();
+    assert_eq!("snapshots with and without sums of squares can't be merged", e.to_string());
+}
+
+#[test]
+fn test_merge_snapshots() {
+    use crate::process_block;
+    use crate::test::TEST_STR_100;
+
+    fn snapshot(block: &[u8], flags: u32) -> Vec<u8> {
+        let (m, _, _) = process_block(block, 100);
+        let mut r = TemperStatResult::new();
+        r.aggregate(&m);
+        let mut buf = Vec::new();
+        r.write_snapshot(&mut buf, flags).unwrap();
+        buf
+    }
+
+    // the same result as processing of the concatenated input
+    let data = TEST_STR_100.as_bytes();
+    let split = data[..data.len() / 2].iter().rposition(|&b| b == b'\n').unwrap() + 1;
+    let (a, b) = (snapshot(&data[..split], 0), snapshot(&data[split..], 0));
+    let whole = snapshot(data, 0);
+
+    let (info, merged) = merge_snapshots(&[&a, &b]).unwrap();
+    let mut buf = Vec::new();
+    merged.write_snapshot(&mut buf, info.flags).unwrap();
+    assert_eq!(whole, buf);
+
+    let strict = snapshot(&data[split..], SNAPSHOT_STRICT);
+    assert_eq!(Some(2), merge_snapshots(&[&a, &b, &strict, &a]).unwrap_err().index);
+    assert_eq!(Some(1), merge_snapshots(&[&a, b"1BRCSNAP"]).unwrap_err().index);
+    assert_eq!(None, merge_snapshots(&[]).unwrap_err().index);
+
+    // strict snapshots of different stations are merged up to `STATIONS_MAX` stations
+    let stations = |range: std::ops::Range<usize>| range.map(|i| format!("s{i};1.0\n")).collect::<String>();
+    let (x, y) = (stations(0..6000), stations(5000..10_001));
+    let (x, y) = (snapshot(x.as_bytes(), SNAPSHOT_STRICT), snapshot(y.as_bytes(), SNAPSHOT_STRICT));
+    assert_eq!(6000, merge_snapshots(&[&x, &x]).unwrap().1.len());
+    let e = merge_snapshots(&[&x, &y]).unwrap_err();
+    assert_eq!(None, e.index);
+    assert!(TooManyStations::is(&e.error));
+    let many = snapshot(stations(0..10_001).as_bytes(), 0);
+    assert_eq!(10_001, merge_snapshots(&[&many]).unwrap().1.len());
+}
+
+#[test]
+fn test_snapshot_histogram() {
+    use crate::{process_block_stats, ParseOptions};
+    use crate::test::TEST_STR_100;
+
+    fn snapshot(block: &[u8]) -> Vec<u8> {
+        let (m, _) = process_block_stats(block, 100, ParseOptions { histogram: true, ..Default::default() }, &mut Default::default());
+        let mut r = TemperStatResult::new();
+        r.aggregate(&m);
+        let mut buf = Vec::new();
+        r.write_snapshot(&mut buf, SNAPSHOT_HISTOGRAM).unwrap();
+        buf
+    }
+
+    let data = TEST_STR_100.as_bytes();
+    let split = data[..data.len() / 2].iter().rposition(|&b| b == b'\n').unwrap() + 1;
+    let (a, b) = (snapshot(&data[..split]), snapshot(&data[split..]));
+    let whole = snapshot(data);
+
+    // histograms are merged bucket by bucket
+    let (info, merged) = merge_snapshots(&[&a, &b]).unwrap();
+    assert_eq!(SNAPSHOT_HISTOGRAM, info.flags);
+    let (_, expected) = TemperStatResult::read_snapshot(&whole).unwrap();
+    for ((_, x), (_, y)) in expected.iter().zip(merged.iter()) {
+        assert!(x.histogram().is_some());
+        assert_eq!(x.histogram(), y.histogram());
+    }
+    let (_, london) = merged.iter().find(|(name, _)| *name == b"London").unwrap();
+    assert_eq!(Some(13), london.median());
+
+    // without histograms the station records can't be written with the flag
+    let (m, _, _) = crate::process_block(data, 100);
+    let mut plain = TemperStatResult::new();
+    plain.aggregate(&m);
+    assert!(plain.write_snapshot(&mut Vec::new(), SNAPSHOT_HISTOGRAM).is_err());
+    let mut buf = Vec::new();
+    plain.write_snapshot(&mut buf, 0).unwrap();
+    let e = merge_snapshots(&[&a, &buf]).unwrap_err();
+    assert_eq!("snapshots with and without histograms can't be merged", e.to_string());
+}
@@ -0,0 +1,208 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use crate::STATIONS_MAX;
+
+/// Reason why a line was rejected by the parser
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ParseError {
+    /// No `;` between station name and temperature
+    MissingDelimiter,
+    /// Temperature is not in `-99.9..=99.9` with one fractional digit
+    BadTemperature,
+    /// Station name is longer than `STATION_NAME_MAX_LEN` bytes
+    NameTooLong,
+    /// Station name is not valid UTF-8
+    InvalidUtf8,
+    /// Station name has zero length
+    EmptyName,
+    /// Valid temperature followed by extra bytes
+    TrailingGarbage,
+    /// Station name contains `;`
+    DelimiterInName,
+}
+
+impl ParseError {
+    pub const COUNT: usize = 7;
+    pub const ALL: [ParseError; ParseError::COUNT] = [
+        ParseError::MissingDelimiter,
+        ParseError::BadTemperature,
+        ParseError::NameTooLong,
+        ParseError::InvalidUtf8,
+        ParseError::EmptyName,
+        ParseError::TrailingGarbage,
+        ParseError::DelimiterInName,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParseError::MissingDelimiter => "missing delimiter",
+            ParseError::BadTemperature => "bad temperature",
+            ParseError::NameTooLong => "name too long",
+            ParseError::InvalidUtf8 => "invalid UTF-8",
+            ParseError::EmptyName => "empty name",
+            ParseError::TrailingGarbage => "trailing garbage",
+            ParseError::DelimiterInName => "delimiter in name",
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Strict mode error: the result has more unique stations than `STATIONS_MAX`.
+/// Returned by `Driver` and `merge_snapshots` wrapped in `io::Error`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooManyStations(pub usize);
+
+impl TooManyStations {
+    /// Error if `stations` is more than the rules allow
+    pub fn check(stations: usize) -> std::io::Result<()> {
+        match stations > STATIONS_MAX {
+            true => Err(Error::new(ErrorKind::InvalidData, TooManyStations(stations))),
+            false => Ok(()),
+        }
+    }
+
+    /// Error `e` was returned by `check`
+    pub fn is(e: &Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<TooManyStations>())
+    }
+}
+
+impl Display for TooManyStations {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} unique stations, maximum is {STATIONS_MAX}", self.0)
+    }
+}
+
+impl std::error::Error for TooManyStations {}
+
+/// Line counters of a block or of the whole run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunStats {
+    pub lines: usize,
+    errors: [usize; ParseError::COUNT],
+}
+
+impl RunStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn reject(&mut self, e: ParseError) {
+        self.errors[e as usize] += 1;
+    }
+
+    pub fn errors(&self, e: ParseError) -> usize {
+        self.errors[e as usize]
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.errors.iter().sum()
+    }
+
+    pub fn accepted(&self) -> usize {
+        self.lines - self.rejected()
+    }
+
+    /// Rejected lines to all lines ratio, `0.0` for no lines
+    pub fn error_ratio(&self) -> f64 {
+        if self.lines == 0 { 0.0 } else { self.rejected() as f64 / self.lines as f64 }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.lines += other.lines;
+        self.errors.iter_mut().zip(other.errors.iter()).for_each(|(a, b)| *a += b);
+    }
+}
+
+impl Display for RunStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lines: {}, accepted: {}, rejected: {}", self.lines, self.accepted(), self.rejected())?;
+        for e in ParseError::ALL {
+            let n = self.errors(e);
+            if n != 0 {
+                write!(f, ", {e}: {n}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do when parse errors exceed the threshold
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorAction {
+    Ignore,
+    /// Print a warning to stderr
+    #[default]
+    Warn,
+    /// Exit with non-zero code
+    Fail,
+}
+
+impl FromStr for ErrorAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(ErrorAction::Ignore),
+            "warn" => Ok(ErrorAction::Warn),
+            "fail" => Ok(ErrorAction::Fail),
+            _ => Err(format!("unknown error action `{s}`")),
+        }
+    }
+}
+
+/// Parse errors policy. Without thresholds any rejected line exceeds the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ErrorPolicy {
+    pub action: ErrorAction,
+    /// Maximum allowed rejected lines
+    pub max_errors: Option<usize>,
+    /// Maximum allowed rejected to all lines ratio
+    pub max_ratio: Option<f64>,
+}
+
+impl ErrorPolicy {
+    pub fn exceeded(&self, stats: &RunStats) -> bool {
+        let rejected = stats.rejected();
+        match (self.max_errors, self.max_ratio) {
+            (None, None) => rejected > 0,
+            (max_errors, max_ratio) => {
+                max_errors.is_some_and(|m| rejected > m) || max_ratio.is_some_and(|r| stats.error_ratio() > r)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_run_stats() {
+    let mut a = RunStats::new();
+    a.lines = 10;
+    a.reject(ParseError::BadTemperature);
+    a.reject(ParseError::BadTemperature);
+    a.reject(ParseError::MissingDelimiter);
+    assert_eq!(3, a.rejected());
+    assert_eq!(7, a.accepted());
+
+    let mut b = RunStats::new();
+    b.lines = 10;
+    b.merge(&a);
+    assert_eq!(20, b.lines);
+    assert_eq!(2, b.errors(ParseError::BadTemperature));
+    assert_eq!(0.15, b.error_ratio());
+    assert_eq!("lines: 20, accepted: 17, rejected: 3, missing delimiter: 1, bad temperature: 2", b.to_string());
+
+    let mut p = ErrorPolicy::default();
+    assert!(p.exceeded(&b));
+    assert!(!p.exceeded(&RunStats::new()));
+    p.max_errors = Some(3);
+    assert!(!p.exceeded(&b));
+    p.max_ratio = Some(0.1);
+    assert!(p.exceeded(&b));
+}
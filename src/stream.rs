@@ -0,0 +1,199 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver};
+use std::thread;
+use memchr::memrchr;
+use crate::aggregator::Aggregator;
+use crate::diagnostics::Diagnostics;
+use crate::{process_block_stats, ParseOptions};
+use crate::result::TemperStatResult;
+use crate::stats::RunStats;
+
+pub const STREAM_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Stream which can't be split into buffers of whole lines, returned wrapped in `io::Error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamError {
+    /// No `\n` in the first buffer of the input
+    MissingNewline(usize),
+    /// A line doesn't fit into a buffer
+    LineTooLong(usize),
+}
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::MissingNewline(size) => write!(f, "no line end in the first {size} bytes"),
+            StreamError::LineTooLong(size) => write!(f, "line longer than {size} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+/// Process not mmappable input, e.g. stdin or pipe.
+///
+/// The reader is read by buffers of `buffer_size` bytes on the calling thread, the partial
+/// last line of a buffer is carried over to the next one. Buffers of whole lines are processed
+/// by `threads` workers, each keeping its own result with owned station names.
+/// As in `process_block`, a last line without `\n` is ignored. A line which doesn't fit into
+/// a buffer is a `StreamError`.
+pub fn process_stream<R: Read, A: Aggregator + Send>(mut reader: R, threads: NonZeroUsize, buffer_size: usize, options: ParseOptions) -> Result<(TemperStatResult<'static, A>, RunStats)> {
+    let buffer_size = buffer_size.max(crate::LINE_MAX_LEN * 2);
+
+    // about two buffers per worker are in flight: queued and in processing
+    let (tx, rx) = sync_channel::<Vec<u8>>(threads.get());
+    let rx = Arc::new(Mutex::new(rx));
+    let (free_tx, free_rx) = channel::<Vec<u8>>();
+
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..threads.get()).map(|_| {
+            let rx = Arc::clone(&rx);
+            let free_tx = free_tx.clone();
+            s.spawn(move || worker(&rx, |buf| { let _ = free_tx.send(buf); }, options))
+        }).collect();
+        drop(free_tx);
+
+        let read = read_buffers(&mut reader, buffer_size, &free_rx, |buf| tx.send(buf).is_ok());
+        drop(tx);
+
+        let mut result = TemperStatResult::new();
+        let mut stats = RunStats::new();
+        for w in workers {
+            let (r, s) = w.join().unwrap();
+            result.merge(&r);
+            stats.merge(&s);
+        }
+
+        read.map(|_| (result, stats))
+    })
+}
+
+/// Read `reader` into buffers ending with `\n` and pass them to `send` until the end of input.
+/// Emptied buffers are taken back from `free` to save allocations.
+pub(crate) fn read_buffers<R: Read>(reader: &mut R, buffer_size: usize, free: &Receiver<Vec<u8>>, mut send: impl FnMut(Vec<u8>) -> bool) -> Result<()> {
+    let mut carry: Vec<u8> = Vec::new();
+    let mut first = true;
+
+    loop {
+        let mut buf = free.try_recv().unwrap_or_else(|_| Vec::with_capacity(buffer_size));
+        buf.clear();
+        buf.extend_from_slice(&carry);
+        carry.clear();
+
+        let eof = fill(reader, &mut buf, buffer_size)?;
+
+        if !eof {
+            match memrchr(b'\n', &buf) {
+                Some(end) => {
+                    carry.extend_from_slice(&buf[end + 1..]);
+                    buf.truncate(end + 1);
+                }
+                // the partial line is carried over, so it must fit into one buffer
+                None => {
+                    let e = if first { StreamError::MissingNewline(buffer_size) } else { StreamError::LineTooLong(buffer_size) };
+                    return Err(Error::new(ErrorKind::InvalidData, e));
+                }
+            }
+        }
+        first = false;
+
+        if !buf.is_empty() && !send(buf) {
+            return Ok(());
+        }
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Read until `buf` has `size` bytes or the end of input, return `true` at the end of input
+fn fill<R: Read>(reader: &mut R, buf: &mut Vec<u8>, size: usize) -> Result<bool> {
+    let size = size.max(buf.len() + 1);
+    let mut len = buf.len();
+    buf.resize(size, 0);
+    while len < size {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => {
+                buf.truncate(len);
+                return Ok(true);
+            }
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                buf.truncate(len);
+                return Err(e);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn worker<A: Aggregator>(rx: &Mutex<Receiver<Vec<u8>>>, free: impl Fn(Vec<u8>), options: ParseOptions) -> (TemperStatResult<'static, A>, RunStats) {
+    let mut result = TemperStatResult::new();
+    let mut stats = RunStats::new();
+
+    loop {
+        let buf = match rx.lock().unwrap().recv() {
+            Ok(buf) => buf,
+            Err(_) => break,
+        };
+        let (map, s) = process_block_stats(&buf, 7000, options, &mut Diagnostics::default());
+        result.aggregate_owned(&map);
+        stats.merge(&s);
+        drop(map);
+        free(buf);
+    }
+
+    (result, stats)
+}
+
+#[test]
+fn test_process_stream() {
+    use crate::{process_block, TemperStat};
+    use crate::test::TEST_STR_100;
+
+    /// Reader returning at most 7 bytes per read
+    struct Chunked<'a>(&'a [u8]);
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = buf.len().min(7).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let data = TEST_STR_100.repeat(50);
+    let (m, lines, _) = process_block::<TemperStat>(data.as_bytes(), 100);
+    let mut expected = TemperStatResult::new();
+    expected.aggregate(&m);
+
+    for threads in [1, 3] {
+        let threads = NonZeroUsize::new(threads).unwrap();
+        let (r, stats) = process_stream::<_, TemperStat>(Chunked(data.as_bytes()), threads, 300, ParseOptions::default()).unwrap();
+        assert_eq!(expected.to_string(), r.to_string());
+        assert_eq!(lines, stats.lines);
+    }
+
+    // the last line without `\n` is ignored
+    let (r, stats) = process_stream::<_, TemperStat>("a;1.0\nb;2.0".as_bytes(), NonZeroUsize::MIN, 1024, ParseOptions::default()).unwrap();
+    assert_eq!("{a=1.0/1.0/1.0}", r.to_string());
+    assert_eq!(1, stats.lines);
+
+    // lines longer than the buffer
+    let stream_error = |data: &[u8]| {
+        let e = process_stream::<_, TemperStat>(data, NonZeroUsize::MIN, 1024, ParseOptions::default()).err().unwrap();
+        *e.get_ref().unwrap().downcast_ref::<StreamError>().unwrap()
+    };
+    assert_eq!(StreamError::MissingNewline(1024), stream_error(&[b'x'; 5000]));
+    let long = ["a;1.0\n".repeat(100), "x".repeat(2000), ";1.0\n".to_string()].concat();
+    assert_eq!(StreamError::LineTooLong(1024), stream_error(long.as_bytes()));
+    // a long line up to the buffer size is parsed
+    let fits = ["a;1.0\n".repeat(100), "x".repeat(900), ";1.0\n".to_string()].concat();
+    let (r, stats) = process_stream::<_, TemperStat>(fits.as_bytes(), NonZeroUsize::MIN, 1024, ParseOptions::default()).unwrap();
+    assert_eq!((101, 2), (stats.lines, r.len()));
+}
@@ -0,0 +1,64 @@
+//! SWAR (SIMD within a register) temperature parsing.
+//!
+//! The temperature bytes are loaded as a little endian `u64` aligned by their length, so the
+//! decimal point is always byte 3 and the last digit byte 4. All digits are validated at once,
+//! then combined by one multiplication: `0x640a0001` adds `100 * d1 + 10 * d2 + d3` at bit 32.
+
+const DIGITS: u64 = 0x0F_000F_0F00;
+const MAGIC: u64 = 0x640a_0001;
+
+/// Parse temperature, accepts and rejects exactly the same input as `i16_from_bytes`
+#[inline]
+pub fn i16_from_bytes_swar(buf: &[u8]) -> Option<i16> {
+    let len = buf.len();
+    if !(3..=5).contains(&len) {
+        return None;
+    }
+    let negative = buf[0] == b'-';
+    // the last byte at byte 4, the decimal point at byte 3
+    let aligned = if len >= 4 {
+        let last = u32::from_le_bytes(buf[len - 4..].try_into().unwrap()) as u64;
+        (last << 8) | if len == 5 { buf[0] as u64 } else { 0 }
+    } else {
+        (u16::from_le_bytes([buf[0], buf[1]]) as u64) << 16 | (buf[2] as u64) << 32
+    };
+
+    // byte 1 is the tens digit, the sign or zero padding
+    let tens = if len == 5 || (len == 4 && !negative) { 0xFF00 } else { 0 };
+    let t = (aligned ^ 0x30_0030_3030) & (0xFF_00FF_0000 | tens);
+    let not_digits = (t & 0xF0_F0F0_F0F0) | (((t & 0x0F_0F0F_0F0F) + 0x06_0606_0606) & 0x10_1010_1010);
+
+    if not_digits != 0 || (aligned >> 24) as u8 != b'.' || (len == 5 && !negative) {
+        return None;
+    }
+
+    let abs = ((t & DIGITS).wrapping_mul(MAGIC) >> 32) & 0x3FF;
+    Some(if negative { -(abs as i16) } else { abs as i16 })
+}
+
+#[test]
+fn test_i16_from_bytes_swar() {
+    use crate::i16_from_bytes;
+
+    for i in -999..=999 {
+        let s = format!("{:.1}", i as f32 / 10.0);
+        assert_eq!(Some(i), i16_from_bytes_swar(s.as_bytes()), "{s}");
+    }
+
+    // all strings up to 5 bytes of digits, signs, decimal point and their neighbours
+    const ALPHABET: &[u8] = b"0159-.+/: a\0\xff";
+    let mut buf = Vec::new();
+    for len in 0..=5 {
+        let mut n: usize = ALPHABET.len().pow(len);
+        while n > 0 {
+            n -= 1;
+            buf.clear();
+            let mut k = n;
+            for _ in 0..len {
+                buf.push(ALPHABET[k % ALPHABET.len()]);
+                k /= ALPHABET.len();
+            }
+            assert_eq!(i16_from_bytes(&buf), i16_from_bytes_swar(&buf), "{:?}", buf.escape_ascii().to_string());
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Open addressing hash table of station statistics.
+//!
+//! The table is made for the 1BRC workload of at most 10,000 stations looked up once per line:
+//! - the name hash is computed by `hash_name` 8 bytes at a time, once `scan::Records` found the name;
+//! - slots keep the hash and the first 16 name bytes inline, names up to 16 bytes are compared
+//!   without reading the name itself, longer names fall back to the full slice comparison;
+//! - lookup and insert are one linear probe: a new station takes the first empty slot found.
+//!
+//! The capacity is a power of two kept at least twice the number of stations, the table grows
+//! only if the input has much more stations than the rules allow.
+use crate::aggregator::Aggregator;
+use crate::TemperStat;
+
+/// Names up to this length are compared by the inline prefix only
+const INLINE_LEN: usize = 16;
+
+/// Hash of occupied slots always has this bit set, `0` marks an empty slot
+const OCCUPIED: u64 = 1 << 63;
+
+const SEED: u64 = 0x517c_c1b7_2722_0a95;
+
+#[derive(Clone, Debug)]
+struct Slot<'a, A> {
+    hash: u64,
+    prefix: [u64; 2],
+    name: &'a [u8],
+    stat: A,
+}
+
+impl<A: Default> Slot<'_, A> {
+    fn empty() -> Self {
+        Slot { hash: 0, prefix: [0; 2], name: &[], stat: A::default() }
+    }
+}
+
+/// Station name to statistics table
+#[derive(Clone, Debug)]
+pub struct StationTable<'a, A = TemperStat> {
+    slots: Vec<Slot<'a, A>>,
+    len: usize,
+}
+
+impl<A: Aggregator> Default for StationTable<'_, A> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<'a, A: Aggregator> StationTable<'a, A> {
+    /// Table for `capacity` stations without growing
+    pub fn new(capacity: usize) -> Self {
+        let slots = (capacity * 2).max(16).next_power_of_two();
+        StationTable { slots: vec![Slot::empty(); slots], len: 0 }
+    }
+
+    /// Number of stations
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Make room for `additional` more stations
+    pub fn reserve(&mut self, additional: usize) {
+        let slots = ((self.len + additional) * 2).next_power_of_two();
+        if slots > self.slots.len() {
+            self.resize(slots);
+        }
+    }
+
+    /// Remove all stations, the capacity is kept
+    pub fn clear(&mut self) {
+        if self.len > 0 {
+            self.slots.fill(Slot::empty());
+            self.len = 0;
+        }
+    }
+
+    /// Stations in unspecified order
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &A)> {
+        self.slots.iter().filter(|s| s.hash != 0).map(|s| (s.name, &s.stat))
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&A> {
+        let hash = hash_name(name);
+        let mask = self.slots.len() - 1;
+        let prefix = prefix(name);
+        let mut i = hash as usize & mask;
+        loop {
+            let s = &self.slots[i];
+            if s.hash == 0 {
+                return None;
+            }
+            if s.hash == hash && s.prefix == prefix && s.name == name {
+                return Some(&s.stat);
+            }
+            i = (i + 1) & mask;
+        }
+    }
+
+    /// Update statistics of station `name` with hash from `hash_name`.
+    /// A new station is inserted with statistics returned by `insert(name)`, if it returns
+    /// an error the station is not inserted and the error is returned.
+    #[inline]
+    pub fn update<E>(&mut self, name: &'a [u8], hash: u64, t: i16, insert: impl FnOnce(&[u8]) -> Result<A, E>) -> Result<(), E> {
+        let hash = hash | OCCUPIED;
+        let prefix = prefix(name);
+        let mask = self.slots.len() - 1;
+        let mut i = hash as usize & mask;
+        loop {
+            let s = &mut self.slots[i];
+            if s.hash == hash && s.prefix == prefix && s.name.len() == name.len() && (name.len() <= INLINE_LEN || s.name == name) {
+                s.stat.update(t);
+                return Ok(());
+            }
+            if s.hash == 0 {
+                let stat = insert(name)?;
+                *s = Slot { hash, prefix, name, stat };
+                self.len += 1;
+                if self.len * 2 > self.slots.len() {
+                    self.resize(self.slots.len() * 2);
+                }
+                return Ok(());
+            }
+            i = (i + 1) & mask;
+        }
+    }
+
+    #[cold]
+    fn resize(&mut self, slots: usize) {
+        let old = std::mem::replace(&mut self.slots, vec![Slot::empty(); slots]);
+        let mask = slots - 1;
+        for s in old.into_iter().filter(|s| s.hash != 0) {
+            let mut i = s.hash as usize & mask;
+            while self.slots[i].hash != 0 {
+                i = (i + 1) & mask;
+            }
+            self.slots[i] = s;
+        }
+    }
+}
+
+/// Little endian word of up to 8 bytes, zero padded
+#[inline(always)]
+pub fn load_word(bytes: &[u8]) -> u64 {
+    if bytes.len() >= 8 {
+        u64::from_le_bytes(bytes[..8].try_into().unwrap())
+    } else {
+        let mut word = [0u8; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        u64::from_le_bytes(word)
+    }
+}
+
+#[inline(always)]
+fn mix(h: u64, word: u64) -> u64 {
+    (h.rotate_left(5) ^ word).wrapping_mul(SEED)
+}
+
+#[inline(always)]
+fn finish(h: u64, len: usize) -> u64 {
+    let h = mix(h, len as u64);
+    (h ^ (h >> 29)) | OCCUPIED
+}
+
+/// First 16 name bytes, zero padded
+#[inline(always)]
+fn prefix(name: &[u8]) -> [u64; 2] {
+    [load_word(name), load_word(name.get(8..).unwrap_or_default())]
+}
+
+/// Hash of the station name
+#[inline]
+pub fn hash_name(name: &[u8]) -> u64 {
+    let h = name.chunks(8).fold(0, |h, c| mix(h, load_word(c)));
+    finish(h, name.len())
+}
+
+#[test]
+fn test_hash_name() {
+    assert_eq!(hash_name(b"Brussels"), hash_name(&b"Brussels;1.0"[..8]));
+    assert_ne!(hash_name(b"Brussels"), hash_name(b"Brussel"));
+    assert_ne!(hash_name(b"a"), hash_name(b"a\0"));
+    assert_ne!(0, hash_name(b"") & OCCUPIED);
+}
+
+#[test]
+fn test_station_table() {
+    let long = "x".repeat(100);
+    let mut names: Vec<String> = (0..20_000).map(|i| format!("station {i}")).collect();
+    names.push(long.clone());
+    names.push(format!("{long}y"));
+    names.push(String::new());
+
+    let mut t: StationTable = StationTable::new(10);
+    for round in 0..3 {
+        for (i, name) in names.iter().enumerate() {
+            let name = name.as_bytes();
+            t.update(name, hash_name(name), (i % 1000) as i16 + round, |_| Ok::<_, ()>(TemperStat::from_i16((i % 1000) as i16 + round))).unwrap();
+        }
+    }
+    assert_eq!(names.len(), t.len());
+    assert_eq!(names.len(), t.iter().count());
+    for (i, name) in names.iter().enumerate() {
+        let s = t.get(name.as_bytes()).unwrap();
+        assert_eq!((3, (i % 1000) as i16, (i % 1000) as i16 + 2), (s.count(), s.min(), s.max()), "{name}");
+    }
+    assert!(t.get(b"station").is_none());
+
+    // rejected new stations are not inserted, known ones are updated, accepted ones take the given statistics
+    assert_eq!(Err("new"), t.update(b"new", hash_name(b"new"), 0, |_| Err("new")));
+    assert_eq!(Ok(()), t.update(b"station 1", hash_name(b"station 1"), 0, |_| Err("new")));
+    assert_eq!(Ok(()), t.update(b"new", hash_name(b"new"), 0, |_| Ok::<_, ()>(TemperStat::from_i16_with_histogram(0))));
+    assert_eq!(Some(0), t.get(b"new").unwrap().median());
+    assert_eq!(names.len() + 1, t.len());
+
+    let slots = t.slots.len();
+    t.clear();
+    assert_eq!((0, slots), (t.iter().count(), t.slots.len()));
+    assert!(t.get(b"new").is_none());
+}